@@ -0,0 +1,116 @@
+use bliss_audio::Analysis;
+use sled::Db;
+
+use std::collections::HashSet;
+
+use crate::db::Stored;
+use crate::error::WusicError;
+
+// Approximate nearest neighbours over analysis vectors, using random hyperplane
+// LSH. Every table hashes a song into a bucket by which side of each hyperplane
+// it falls on, so songs with a small cosine distance tend to share buckets.
+// Entries live in the "ann" tree as `table bucket id` keys.
+const TABLES: u8 = 8;
+const BITS: u32 = 12;
+const SEED: u64 = 0x7775_7369_635f_616e;
+
+// Parameters the index was built with, so a change forces a rebuild
+pub fn params() -> Vec<u8> {
+    let mut params = vec![TABLES, BITS as u8];
+    params.extend_from_slice(&SEED.to_be_bytes());
+    params
+}
+
+// Hyperplanes of a table, generated from the seed so they never need storing
+fn hyperplanes(table: u8, dims: usize) -> Vec<Vec<f32>> {
+    let mut state = SEED ^ (table as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    let mut uniform = || {
+        // xorshift64*, mapped to (0, 1]
+        state ^= state >> 12;
+        state ^= state << 25;
+        state ^= state >> 27;
+        let r = state.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        ((r >> 11) as f64 + 1.0) / (1u64 << 53) as f64
+    };
+
+    (0..BITS)
+        .map(|_| {
+            (0..dims)
+                .map(|_| {
+                    // Box-Muller for normally distributed components
+                    let (u1, u2) = (uniform(), uniform());
+                    ((-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()) as f32
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// Bucket of a feature vector in a table
+fn bucket(table: u8, features: &[f32]) -> u16 {
+    hyperplanes(table, features.len())
+        .iter()
+        .enumerate()
+        .fold(0, |bucket, (i, plane)| {
+            let dot: f32 = plane.iter().zip(features).map(|(p, f)| p * f).sum();
+            if dot >= 0.0 {
+                bucket | (1 << i)
+            } else {
+                bucket
+            }
+        })
+}
+
+// Key of a song in the index
+fn key(table: u8, bucket: u16, id: u128) -> Vec<u8> {
+    let mut key = vec![table];
+    key.extend_from_slice(&bucket.to_be_bytes());
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+// Adds a song to the index
+pub fn insert(db: &Db, stored: &Stored) -> Result<(), WusicError> {
+    let tree = db.open_tree("ann")?;
+    let features = stored.analysis.to_vec();
+    for table in 0..TABLES {
+        tree.insert(key(table, bucket(table, &features), stored.id), b"")?;
+    }
+
+    Ok(())
+}
+
+// Removes a song from the index
+pub fn remove(db: &Db, stored: &Stored) -> Result<(), WusicError> {
+    let tree = db.open_tree("ann")?;
+    let features = stored.analysis.to_vec();
+    for table in 0..TABLES {
+        tree.remove(key(table, bucket(table, &features), stored.id))?;
+    }
+
+    Ok(())
+}
+
+// Finds songs that are likely to be close to an analysis
+pub fn candidates(db: &Db, analysis: &Analysis) -> Result<HashSet<u128>, WusicError> {
+    let tree = db.open_tree("ann")?;
+    let features = analysis.to_vec();
+
+    let mut candidates = HashSet::new();
+    for table in 0..TABLES {
+        // Probe the bucket and every bucket one bit away from it
+        let bucket = bucket(table, &features);
+        for probe in std::iter::once(bucket).chain((0..BITS).map(|b| bucket ^ (1 << b))) {
+            let mut prefix = vec![table];
+            prefix.extend_from_slice(&probe.to_be_bytes());
+            for entry in tree.scan_prefix(prefix) {
+                let (k, _) = entry?;
+                let mut id = [0; 16];
+                id.copy_from_slice(&k[3..]);
+                candidates.insert(u128::from_be_bytes(id));
+            }
+        }
+    }
+
+    Ok(candidates)
+}
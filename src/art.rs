@@ -0,0 +1,144 @@
+use ffmpeg::codec;
+use ffmpeg::format::stream::Disposition;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::WusicError;
+
+// Files in a song's directory that are used as art, in order of preference
+const ART_NAMES: [&str; 4] = ["cover", "folder", "front", "album"];
+
+// Cover art of a song
+pub struct Picture {
+    pub data: Vec<u8>,
+    pub mime: &'static str,
+}
+
+impl Picture {
+    // Finds the art of a song, either embedded in it or next to it
+    pub fn find(path: &Path) -> Result<Option<Picture>, WusicError> {
+        if let Some(picture) = Picture::embedded(path)? {
+            return Ok(Some(picture));
+        }
+
+        let dir = match path.parent() {
+            Some(dir) => dir,
+            None => return Ok(None),
+        };
+        let mut candidates: Vec<(usize, PathBuf)> = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .map(|n| n.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            if let Some((stem, ext)) = name.rsplit_once('.') {
+                if let Some(rank) = ART_NAMES.iter().position(|n| *n == stem) {
+                    if ["jpg", "jpeg", "png"].contains(&ext) {
+                        candidates.push((rank, path));
+                    }
+                }
+            }
+        }
+        candidates.sort();
+
+        match candidates.into_iter().next() {
+            Some((_, path)) => Ok(Some(Picture::from_file(&path)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Extracts the attached picture of a song
+    fn embedded(path: &Path) -> Result<Option<Picture>, WusicError> {
+        let mut format = ffmpeg::format::input(&path)?;
+        let (index, mime) = match format
+            .streams()
+            .find(|s| s.disposition().contains(Disposition::ATTACHED_PIC))
+        {
+            Some(stream) => (
+                stream.index(),
+                match stream.parameters().id() {
+                    codec::Id::PNG => "image/png",
+                    _ => "image/jpeg",
+                },
+            ),
+            None => return Ok(None),
+        };
+
+        for (stream, packet) in format.packets() {
+            if stream.index() == index {
+                if let Some(data) = packet.data() {
+                    return Ok(Some(Picture {
+                        data: data.to_vec(),
+                        mime,
+                    }));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Loads art from an image file
+    pub fn from_file(path: &Path) -> Result<Picture, WusicError> {
+        let png = path
+            .extension()
+            .map(|e| e.eq_ignore_ascii_case("png"))
+            .unwrap_or(false);
+
+        Ok(Picture {
+            data: fs::read(path)?,
+            mime: if png { "image/png" } else { "image/jpeg" },
+        })
+    }
+
+    // Loads art from the store
+    pub fn load(msp: &Path, art: &str) -> Result<Picture, WusicError> {
+        Picture::from_file(&art_path(msp, art))
+    }
+
+    // Saves art into the store, returning its name there
+    pub fn store(&self, msp: &Path) -> Result<String, WusicError> {
+        let art = format!(
+            "{}.{}",
+            blake3::hash(&self.data).to_hex(),
+            if self.mime == "image/png" {
+                "png"
+            } else {
+                "jpg"
+            }
+        );
+
+        // Identical art is only stored once
+        let path = art_path(msp, &art);
+        if !path.exists() {
+            fs::create_dir_all(msp.join("art"))?;
+            fs::write(&path, &self.data)?;
+        }
+
+        Ok(art)
+    }
+
+    // Base64 encoded FLAC picture block, for METADATA_BLOCK_PICTURE
+    pub fn block(&self) -> String {
+        let mut block = Vec::new();
+        // Front cover
+        block.extend_from_slice(&3u32.to_be_bytes());
+        block.extend_from_slice(&(self.mime.len() as u32).to_be_bytes());
+        block.extend_from_slice(self.mime.as_bytes());
+        // No description
+        block.extend_from_slice(&0u32.to_be_bytes());
+        // Unknown width, height, depth and colors
+        block.extend_from_slice(&[0; 16]);
+        block.extend_from_slice(&(self.data.len() as u32).to_be_bytes());
+        block.extend_from_slice(&self.data);
+
+        base64::encode(block)
+    }
+}
+
+// Path of art in the store
+pub fn art_path(msp: &Path, art: &str) -> PathBuf {
+    msp.join("art").join(art)
+}
@@ -0,0 +1,302 @@
+use bliss_audio::distance::cosine_distance;
+use bliss_audio::Analysis;
+use rust_fuzzy_search::fuzzy_compare;
+use sled::Db;
+use ulid::Ulid;
+
+use crate::ann;
+use crate::error::WusicError;
+use crate::schema::{decode, encode};
+
+pub use crate::schema::Stored;
+
+// Secondary indexes, each a tree of `normalized value \0 id` keys
+pub const INDEXES: [&str; 3] = ["artist", "album", "title"];
+
+// Marks which set of indexes was built, bumped when one is added
+const INDEXED: &[u8] = b"2";
+
+impl Stored {
+    // Values of the secondary indexes, in the same order as INDEXES
+    fn index_values(&self) -> [&str; 3] {
+        [&self.tags.artist, &self.tags.album, &self.tags.title]
+    }
+}
+
+// Gets a stored song from the database
+pub fn get_stored(db: &Db, id: u128) -> Result<Option<Stored>, WusicError> {
+    match db.get(id.to_be_bytes())? {
+        Some(v) => Ok(Some(decode(&v)?)),
+        None => Ok(None),
+    }
+}
+
+// Makes an id for a new song
+pub fn new_id() -> u128 {
+    Ulid::new().into()
+}
+
+// Id for a song that was keyed by its phash, the same every time so a rerun of an
+// interrupted migrate finds what it already moved. When the song was stored isn't
+// known, so the timestamp is left at zero.
+pub fn legacy_id(key: u128) -> u128 {
+    let hash = blake3::hash(&key.to_be_bytes());
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&hash.as_bytes()[..16]);
+    u128::from_be_bytes(bytes) & ((1 << 80) - 1)
+}
+
+// Iterates over every stored song
+pub fn iter_stored(db: &Db) -> impl Iterator<Item = Result<Stored, WusicError>> {
+    db.iter().filter_map(|f| f.ok()).map(|(_, v)| decode(&v))
+}
+
+// Inserts a song into the database, keeping the indexes up to date
+pub fn insert_stored(db: &Db, stored: &Stored) -> Result<(), WusicError> {
+    if let Some(old) = db.insert(stored.id.to_be_bytes(), encode(stored)?)? {
+        // A broken old record has nothing worth unindexing
+        if let Ok(old) = decode(&old) {
+            unindex(db, &old)?;
+        }
+    }
+    index(db, stored)
+}
+
+// Removes a song from the database, keeping the indexes up to date
+pub fn remove_stored(db: &Db, stored: &Stored) -> Result<(), WusicError> {
+    db.remove(stored.id.to_be_bytes())?;
+    unindex(db, stored)
+}
+
+// Adds a song to the indexes
+fn index(db: &Db, stored: &Stored) -> Result<(), WusicError> {
+    for (index, value) in INDEXES.iter().zip(stored.index_values()) {
+        db.open_tree(index)?
+            .insert(index_key(value, stored.id), b"")?;
+    }
+    db.open_tree("phash")?
+        .insert(phash_key(stored.phash, stored.id), b"")?;
+    ann::insert(db, stored)
+}
+
+// Removes a song from the indexes
+fn unindex(db: &Db, stored: &Stored) -> Result<(), WusicError> {
+    for (index, value) in INDEXES.iter().zip(stored.index_values()) {
+        db.open_tree(index)?.remove(index_key(value, stored.id))?;
+    }
+    db.open_tree("phash")?
+        .remove(phash_key(stored.phash, stored.id))?;
+    ann::remove(db, stored)
+}
+
+// Builds the indexes if they haven't been built yet
+pub fn ensure_indexes(db: &Db) -> Result<(), WusicError> {
+    let meta = db.open_tree("meta")?;
+    if meta.get("indexed")?.as_deref() != Some(INDEXED)
+        || meta.get("ann")?.as_deref() != Some(&ann::params()[..])
+    {
+        rebuild_indexes(db)?;
+    }
+
+    Ok(())
+}
+
+// Rebuilds all indexes from the stored songs
+pub fn rebuild_indexes(db: &Db) -> Result<(), WusicError> {
+    let meta = db.open_tree("meta")?;
+    meta.remove("indexed")?;
+
+    for index in INDEXES.iter().chain(&["phash", "ann"]) {
+        db.open_tree(index)?.clear()?;
+    }
+    for (k, v) in db.iter().filter_map(|f| f.ok()) {
+        match decode(&v) {
+            Ok(stored) => index(db, &stored)?,
+            Err(e) => eprintln!("{} \t| not indexed! {}", fmt_key(&k), e),
+        }
+    }
+
+    meta.insert("indexed", INDEXED)?;
+    meta.insert("ann", ann::params())?;
+
+    Ok(())
+}
+
+// Looks up the ids of songs with a tag in an index
+pub fn lookup(db: &Db, index: &str, value: &str) -> Result<Vec<u128>, WusicError> {
+    let mut prefix = normalize(value).into_bytes();
+    prefix.push(0);
+
+    let mut ids = Vec::new();
+    for entry in db.open_tree(index)?.scan_prefix(prefix) {
+        let (k, _) = entry?;
+        ids.push(split_index_key(&k).1);
+    }
+
+    Ok(ids)
+}
+
+// Looks up the ids of songs with a phash
+pub fn lookup_phash(db: &Db, phash: u128) -> Result<Vec<u128>, WusicError> {
+    let mut ids = Vec::new();
+    for entry in db.open_tree("phash")?.scan_prefix(phash.to_be_bytes()) {
+        let (k, _) = entry?;
+        let mut id = [0; 16];
+        id.copy_from_slice(&k[16..]);
+        ids.push(u128::from_be_bytes(id));
+    }
+
+    Ok(ids)
+}
+
+// Formats an id as a ULID
+pub fn fmt_id(id: u128) -> String {
+    Ulid::from(id).to_string()
+}
+
+// Parses an id formatted as a ULID
+pub fn parse_id(s: &str) -> Option<u128> {
+    Ulid::from_string(s).ok().map(u128::from)
+}
+
+// Formats a raw db key as an id
+pub fn fmt_key(key: &[u8]) -> String {
+    fmt_id(u128::from_be_bytes(key.try_into().unwrap_or_default()))
+}
+
+// Normalizes a tag for the indexes
+pub fn normalize(value: &str) -> String {
+    value
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+// Key of a song in an index
+fn index_key(value: &str, id: u128) -> Vec<u8> {
+    let mut key = normalize(value).into_bytes();
+    key.push(0);
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+// Key of a song in the phash index
+fn phash_key(phash: u128, id: u128) -> Vec<u8> {
+    let mut key = phash.to_be_bytes().to_vec();
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+// Splits an index key back into its normalized value and id
+fn split_index_key(key: &[u8]) -> (String, u128) {
+    let (value, id) = key.split_at(key.len() - 16);
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(id);
+    (
+        String::from_utf8_lossy(&value[..value.len() - 1]).into_owned(),
+        u128::from_be_bytes(bytes),
+    )
+}
+
+// Finds the closest matching song from the database
+pub fn find_closest_song(
+    db: &Db,
+    title: &str,
+    analysis: &Analysis,
+) -> Result<(u128, f32, u128, f32), WusicError> {
+    let mut closest: u128 = 0;
+    let mut closest_dist = f32::MAX;
+
+    let mut tclosest: u128 = 0;
+    let mut tclosest_dist = 0.0;
+
+    // Perceptually closer
+    if let Some((dist, stored)) = find_closest_songs(db, analysis, None, 1)?.pop() {
+        closest_dist = dist;
+        closest = stored.id;
+    }
+
+    // Title closer, straight from the title index
+    let title = normalize(title);
+    for entry in db.open_tree("title")?.iter() {
+        let (k, _) = entry?;
+        let (stored_title, id) = split_index_key(&k);
+        let tdist = fuzzy_compare(&title, &stored_title);
+        if tdist > tclosest_dist {
+            tclosest_dist = tdist;
+            tclosest = id;
+        }
+    }
+
+    Ok((closest, closest_dist, tclosest, tclosest_dist))
+}
+
+// Finds the n closest songs to an analysis, closest first
+pub fn find_closest_songs(
+    db: &Db,
+    analysis: &Analysis,
+    exclude: Option<u128>,
+    n: usize,
+) -> Result<Vec<(f32, Stored)>, WusicError> {
+    let mut closest: Vec<(f32, Stored)> = Vec::new();
+    if n == 0 {
+        return Ok(closest);
+    }
+
+    // Only look at likely candidates, unless there aren't enough of them
+    let mut candidates = ann::candidates(db, analysis)?;
+    if let Some(exclude) = exclude {
+        candidates.remove(&exclude);
+    }
+    let songs: Box<dyn Iterator<Item = Result<Stored, WusicError>>> = if candidates.len() >= n {
+        Box::new(
+            candidates
+                .into_iter()
+                .filter_map(|id| get_stored(db, id).transpose())
+                .collect::<Vec<_>>()
+                .into_iter(),
+        )
+    } else {
+        Box::new(iter_stored(db))
+    };
+
+    for stored in songs {
+        let stored = stored?;
+        if Some(stored.id) == exclude {
+            continue;
+        }
+
+        // Keep only the n closest
+        let dist = stored.analysis.custom_distance(analysis, cosine_distance);
+        if closest.len() < n || dist < closest[closest.len() - 1].0 {
+            let i = closest.partition_point(|(d, _)| *d <= dist);
+            closest.insert(i, (dist, stored));
+            closest.truncate(n);
+        }
+    }
+
+    Ok(closest)
+}
+
+// Finds the song with the closest matching title
+pub fn find_by_title(db: &Db, title: &str) -> Result<Option<Stored>, WusicError> {
+    // Exact matches come straight from the index
+    if let Some(id) = lookup(db, "title", title)?.first() {
+        return get_stored(db, *id);
+    }
+
+    let mut tclosest = None;
+    let mut tclosest_dist = 0.0;
+
+    for stored in iter_stored(db) {
+        let stored = stored?;
+        let tdist = fuzzy_compare(title, &stored.tags.title);
+        if tdist > tclosest_dist {
+            tclosest_dist = tdist;
+            tclosest = Some(stored);
+        }
+    }
+
+    Ok(tclosest)
+}
@@ -0,0 +1,90 @@
+use std::{error::Error, fmt, io};
+
+// Anything that can go wrong while handling a song
+#[derive(Debug)]
+pub enum WusicError {
+    Decode(bliss_audio::BlissError),
+    Ffmpeg(ffmpeg::Error),
+    Db(sled::Error),
+    Serialize(bincode::Error),
+    Json(serde_json::Error),
+    Io(io::Error),
+    UnknownVersion(u16),
+    UnknownLayout(String),
+    BadTemplate(String),
+    UnknownProfile(String),
+}
+
+impl fmt::Display for WusicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WusicError::Decode(e) => write!(f, "decode error: {}", e),
+            WusicError::Ffmpeg(e) => write!(f, "ffmpeg error: {}", e),
+            WusicError::Db(e) => write!(f, "db error: {}", e),
+            WusicError::Serialize(e) => write!(f, "record error: {}", e),
+            WusicError::Json(e) => write!(f, "json error: {}", e),
+            WusicError::Io(e) => write!(f, "io error: {}", e),
+            WusicError::UnknownVersion(v) => {
+                write!(f, "record has unknown version {}, is wusic outdated?", v)
+            }
+            WusicError::UnknownLayout(l) => {
+                write!(f, "store has unknown layout {}, is wusic outdated?", l)
+            }
+            WusicError::BadTemplate(t) => write!(f, "bad template {}", t),
+            WusicError::UnknownProfile(p) => write!(f, "profile {} not defined!", p),
+        }
+    }
+}
+
+impl Error for WusicError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WusicError::Decode(e) => Some(e),
+            WusicError::Ffmpeg(e) => Some(e),
+            WusicError::Db(e) => Some(e),
+            WusicError::Serialize(e) => Some(e),
+            WusicError::Json(e) => Some(e),
+            WusicError::Io(e) => Some(e),
+            WusicError::UnknownVersion(_) => None,
+            WusicError::UnknownLayout(_) => None,
+            WusicError::BadTemplate(_) => None,
+            WusicError::UnknownProfile(_) => None,
+        }
+    }
+}
+
+impl From<bliss_audio::BlissError> for WusicError {
+    fn from(e: bliss_audio::BlissError) -> Self {
+        WusicError::Decode(e)
+    }
+}
+
+impl From<ffmpeg::Error> for WusicError {
+    fn from(e: ffmpeg::Error) -> Self {
+        WusicError::Ffmpeg(e)
+    }
+}
+
+impl From<sled::Error> for WusicError {
+    fn from(e: sled::Error) -> Self {
+        WusicError::Db(e)
+    }
+}
+
+impl From<bincode::Error> for WusicError {
+    fn from(e: bincode::Error) -> Self {
+        WusicError::Serialize(e)
+    }
+}
+
+impl From<serde_json::Error> for WusicError {
+    fn from(e: serde_json::Error) -> Self {
+        WusicError::Json(e)
+    }
+}
+
+impl From<io::Error> for WusicError {
+    fn from(e: io::Error) -> Self {
+        WusicError::Io(e)
+    }
+}
@@ -0,0 +1,487 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::art::Picture;
+use crate::db::{fmt_id, Stored};
+use crate::error::WusicError;
+use crate::store::{remove_empty_dirs, sanitize, Store};
+use crate::transcode::transcode;
+
+// Records what an export put in its directory, so reruns only change what changed
+const MANIFEST: &str = ".wusic-export.json";
+
+// Songs recorded in the manifest at a time, before they are made
+const BATCH: usize = 100;
+
+pub const DEFAULT_TEMPLATE: &str = "{albumartist}/{album}/{track} {title}";
+
+// How songs are put into an export
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Mode {
+    Symlink,
+    Hardlink,
+    Copy,
+    // Encoded with a profile, or copied if the song already uses it
+    Transcode(String),
+}
+
+impl Mode {
+    pub const NAMES: [&'static str; 3] = ["symlink", "hardlink", "copy"];
+
+    // Name recorded in the manifest, so changing how songs are put in redoes them
+    pub fn name(&self) -> String {
+        match self {
+            Mode::Symlink => "symlink".to_owned(),
+            Mode::Hardlink => "hardlink".to_owned(),
+            Mode::Copy => "copy".to_owned(),
+            Mode::Transcode(profile) => format!("transcode {}", profile),
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Mode> {
+        match name {
+            "symlink" => Some(Mode::Symlink),
+            "hardlink" => Some(Mode::Hardlink),
+            "copy" => Some(Mode::Copy),
+            _ => None,
+        }
+    }
+
+    // Extension of a song once it is put into an export
+    fn extension<'a>(
+        &'a self,
+        store: &'a Store,
+        stored: &'a Stored,
+    ) -> Result<&'a str, WusicError> {
+        match self {
+            Mode::Transcode(profile) => store.profiles.extension(profile),
+            _ => store.profiles.extension(&stored.profile),
+        }
+    }
+
+    // Puts a song from the store at a path
+    fn make(
+        &self,
+        store: &Store,
+        stored: &Stored,
+        source: &Path,
+        path: &Path,
+    ) -> Result<(), WusicError> {
+        match self {
+            Mode::Symlink => Ok(std::os::unix::fs::symlink(source, path)?),
+            Mode::Hardlink => Ok(fs::hard_link(source, path)?),
+            _ => {
+                // Write next to the target first, so a cut off copy is never taken as done
+                let mut part = path.as_os_str().to_owned();
+                part.push(".part");
+                let part = PathBuf::from(part);
+                let result = self
+                    .write(store, stored, source, &part)
+                    .and_then(|_| Ok(fs::rename(&part, path)?));
+                if result.is_err() {
+                    let _ = fs::remove_file(&part);
+                }
+                result
+            }
+        }
+    }
+
+    // Copies or transcodes a song from the store
+    fn write(
+        &self,
+        store: &Store,
+        stored: &Stored,
+        source: &Path,
+        path: &Path,
+    ) -> Result<(), WusicError> {
+        match self {
+            Mode::Transcode(name) if *name != stored.profile => {
+                let profile = store.profiles.profile(name)?;
+                let picture = stored
+                    .art
+                    .as_ref()
+                    .map(|art| Picture::load(&store.path, art))
+                    .transpose()?;
+                let gain = match &stored.loudness {
+                    Some(loudness) => loudness.tags(profile),
+                    None => Vec::new(),
+                };
+                let picture = picture.as_ref().filter(|_| profile.vorbis_comments());
+                transcode(
+                    source,
+                    path,
+                    profile,
+                    stored.tags.metadata(profile, picture, &gain),
+                )
+            }
+            _ => {
+                fs::copy(source, path)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+// A tag that can be used in a template
+#[derive(Clone, Copy)]
+enum Field {
+    Title,
+    Artist,
+    Album,
+    AlbumArtist,
+    Track,
+    Disc,
+    Date,
+    Genre,
+    Composer,
+    Id,
+}
+
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+// Names songs in an export, like "{albumartist}/{album}/{track} {title}"
+pub struct Template {
+    // Parts of each directory and then the file name
+    segments: Vec<Vec<Part>>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Template, WusicError> {
+        let mut segments = Vec::new();
+        for segment in template.split('/').filter(|s| !s.is_empty()) {
+            let mut parts = Vec::new();
+            let mut rest = segment;
+            while let Some(start) = rest.find('{') {
+                if start > 0 {
+                    parts.push(Part::Literal(rest[..start].to_owned()));
+                }
+                let end = rest[start..]
+                    .find('}')
+                    .ok_or_else(|| WusicError::BadTemplate(template.to_owned()))?
+                    + start;
+                parts.push(Part::Field(match &rest[start + 1..end] {
+                    "title" => Field::Title,
+                    "artist" => Field::Artist,
+                    "album" => Field::Album,
+                    "albumartist" => Field::AlbumArtist,
+                    "track" => Field::Track,
+                    "disc" => Field::Disc,
+                    "date" => Field::Date,
+                    "genre" => Field::Genre,
+                    "composer" => Field::Composer,
+                    "id" => Field::Id,
+                    _ => return Err(WusicError::BadTemplate(template.to_owned())),
+                }));
+                rest = &rest[end + 1..];
+            }
+            if !rest.is_empty() {
+                parts.push(Part::Literal(rest.to_owned()));
+            }
+            segments.push(parts);
+        }
+
+        if segments.is_empty() {
+            return Err(WusicError::BadTemplate(template.to_owned()));
+        }
+        Ok(Template { segments })
+    }
+
+    // Relative path of a song, without an extension
+    fn render(&self, stored: &Stored) -> PathBuf {
+        let tags = &stored.tags;
+        let value = |field: Field| match field {
+            Field::Title => tags.title.clone(),
+            Field::Artist => tags.artist.clone(),
+            Field::Album => tags.album.clone(),
+            Field::AlbumArtist if tags.album_artist.is_empty() => tags.artist.clone(),
+            Field::AlbumArtist => tags.album_artist.clone(),
+            Field::Track => tags.track.map(|t| format!("{:02}", t)).unwrap_or_default(),
+            Field::Disc => tags.disc.map(|d| d.to_string()).unwrap_or_default(),
+            Field::Date => tags.date.clone(),
+            Field::Genre => tags.genre.clone(),
+            Field::Composer => tags.composer.clone(),
+            Field::Id => fmt_id(stored.id),
+        };
+
+        let mut path = PathBuf::new();
+        for parts in &self.segments {
+            let segment: String = parts
+                .iter()
+                .map(|part| match part {
+                    Part::Literal(literal) => literal.clone(),
+                    // Tags can't add directories of their own
+                    Part::Field(field) => value(*field).replace('/', "_"),
+                })
+                .collect();
+            path.push(sanitize(&segment, "Unknown"));
+        }
+        path
+    }
+}
+
+// Relative path of a song in an export, with its id added if another song has the name
+fn unique_name(
+    template: &Template,
+    stored: &Stored,
+    extension: &str,
+    taken: &mut HashSet<PathBuf>,
+) -> PathBuf {
+    let mut name = template.render(stored);
+    let mut file = name
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    if !taken.insert(name.clone()) {
+        file = format!("{} [{}]", file, fmt_id(stored.id));
+    }
+    name.set_file_name(format!("{}.{}", file, extension));
+    name
+}
+
+// A song put into an export
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct Entry {
+    pub id: String,
+    pub source: PathBuf,
+    // blake3 hash of the song in the store when it was exported
+    pub fhash: String,
+    pub mode: String,
+}
+
+// Everything an export put in its directory, by relative path
+#[derive(Serialize, Deserialize, Default)]
+pub struct Manifest {
+    pub entries: BTreeMap<PathBuf, Entry>,
+}
+
+impl Manifest {
+    pub fn load(dir: &Path) -> Result<Manifest, WusicError> {
+        match fs::read(dir.join(MANIFEST)) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Manifest::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Whether an export was made into a directory before
+    pub fn exists(dir: &Path) -> bool {
+        dir.join(MANIFEST).is_file()
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<(), WusicError> {
+        let tmp = dir.join(format!("{}.tmp", MANIFEST));
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp, dir.join(MANIFEST))?;
+        Ok(())
+    }
+}
+
+// Mirrors songs from the store into a directory, named by a template, and removes
+// whatever an earlier export put there that isn't wanted anymore
+pub fn export(
+    store: &Store,
+    dir: &Path,
+    songs: &[Stored],
+    template: &Template,
+    mode: &Mode,
+) -> Result<Vec<(String, WusicError)>, WusicError> {
+    fs::create_dir_all(dir)?;
+    let mut manifest = Manifest::load(dir)?;
+
+    // Where every song should end up, songs with the same name get their id added
+    let mut wanted = BTreeMap::new();
+    let mut taken = HashSet::new();
+    for stored in songs {
+        let extension = mode.extension(store, stored)?;
+        let name = unique_name(template, stored, extension, &mut taken);
+        wanted.insert(
+            name,
+            (
+                Entry {
+                    id: fmt_id(stored.id),
+                    source: store.song_path(stored)?,
+                    fhash: blake3::Hash::from(stored.fhash).to_hex().to_string(),
+                    mode: mode.name(),
+                },
+                stored,
+            ),
+        );
+    }
+
+    // Remove stale entries first, so their names can be reused
+    let mut failed = Vec::new();
+    let stale: Vec<PathBuf> = manifest
+        .entries
+        .iter()
+        .filter(|(name, entry)| wanted.get(*name).map(|(e, _)| e) != Some(entry))
+        .map(|(name, _)| name.clone())
+        .collect();
+    for name in stale {
+        let path = dir.join(&name);
+        if path.symlink_metadata().is_ok() {
+            // Stays in the manifest, so the next run tries again
+            if let Err(e) = fs::remove_file(&path) {
+                println!("{} \t| failed to remove! {}", name.display(), e);
+                failed.push((name.display().to_string(), e.into()));
+                continue;
+            }
+            remove_empty_dirs(dir, &path);
+        }
+        manifest.entries.remove(&name);
+        println!("{} \t| removed", name.display());
+    }
+    manifest.save(dir)?;
+
+    let todo: Vec<(PathBuf, (Entry, &Stored))> = wanted
+        .into_iter()
+        .filter(|(name, (entry, _))| {
+            manifest.entries.get(name) != Some(entry) || dir.join(name).symlink_metadata().is_err()
+        })
+        .collect();
+
+    // Songs are recorded before they are made, so a crash leaves files the next run
+    // knows it made rather than ones it won't touch
+    for batch in todo.chunks(BATCH) {
+        let mut recorded = Vec::new();
+        for (name, (entry, stored)) in batch {
+            let path = dir.join(name);
+            // Never replace files the export didn't make
+            if !manifest.entries.contains_key(name) && path.symlink_metadata().is_ok() {
+                let e = WusicError::Io(std::io::ErrorKind::AlreadyExists.into());
+                println!("{} \t| failed to export! {}", name.display(), e);
+                failed.push((name.display().to_string(), e));
+                continue;
+            }
+            let previous = manifest.entries.insert(name.clone(), entry.clone());
+            recorded.push((name, entry, *stored, previous));
+        }
+        manifest.save(dir)?;
+
+        for (name, entry, stored, previous) in recorded {
+            let path = dir.join(name);
+            let result = path
+                .parent()
+                .map(fs::create_dir_all)
+                .transpose()
+                .map_err(WusicError::from)
+                .and_then(|_| mode.make(store, stored, &entry.source, &path));
+            match result {
+                Ok(()) => println!("{} \t| exported", name.display()),
+                Err(e) => {
+                    println!("{} \t| failed to export! {}", name.display(), e);
+                    failed.push((name.display().to_string(), e));
+                    match previous {
+                        Some(previous) => manifest.entries.insert(name.clone(), previous),
+                        None => manifest.entries.remove(name),
+                    };
+                }
+            }
+        }
+        manifest.save(dir)?;
+    }
+
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Tags;
+    use bliss_audio::Analysis;
+
+    fn song(id: u128, tags: Tags) -> Stored {
+        Stored {
+            id,
+            fhash: [0; 32],
+            ahash: None,
+            phash: 0,
+            fingerprint: None,
+
+            tags,
+            duration: None,
+            art: None,
+            profile: "opus160".to_owned(),
+            loudness: None,
+
+            analysis: Analysis::default(),
+        }
+    }
+
+    fn tags(artist: &str, album: &str, track: Option<u32>, title: &str) -> Tags {
+        Tags {
+            title: title.to_owned(),
+            artist: artist.to_owned(),
+            album: album.to_owned(),
+            track,
+            ..Tags::default()
+        }
+    }
+
+    #[test]
+    fn bad_templates() {
+        for template in ["", "/", "//", "{title", "{nope}/{title}", "{artist}/{}"] {
+            assert!(Template::parse(template).is_err(), "{:?} parsed", template);
+        }
+    }
+
+    #[test]
+    fn renders_fields() {
+        let template = Template::parse(DEFAULT_TEMPLATE).unwrap();
+        let stored = song(1, tags("Artist", "Album", Some(3), "Title"));
+        assert_eq!(
+            template.render(&stored),
+            PathBuf::from("Artist/Album/03 Title")
+        );
+
+        let mut album = tags("Artist", "Album", None, "Title");
+        album.album_artist = "Various".to_owned();
+        assert_eq!(
+            template.render(&song(1, album)),
+            PathBuf::from("Various/Album/Title")
+        );
+
+        let template = Template::parse("{id}").unwrap();
+        assert_eq!(template.render(&stored), PathBuf::from(fmt_id(1)));
+    }
+
+    #[test]
+    fn tags_stay_in_their_directory() {
+        let template = Template::parse(DEFAULT_TEMPLATE).unwrap();
+        let stored = song(1, tags("AC/DC", "..", Some(1), "../../etc/passwd"));
+        let path = template.render(&stored);
+        assert_eq!(path, PathBuf::from("AC_DC/Unknown/01 .._.._etc_passwd"));
+        assert!(path
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_))));
+
+        // Empty tags don't leave empty directories
+        let stored = song(1, tags("", "", None, ""));
+        assert_eq!(
+            template.render(&stored),
+            PathBuf::from("Unknown/Unknown/Unknown")
+        );
+    }
+
+    #[test]
+    fn duplicate_names_get_ids() {
+        let template = Template::parse(DEFAULT_TEMPLATE).unwrap();
+        let mut taken = HashSet::new();
+        let first = song(1, tags("Artist", "Album", Some(1), "Title"));
+        let second = song(2, tags("Artist", "Album", Some(1), "Title"));
+        assert_eq!(
+            unique_name(&template, &first, "opus", &mut taken),
+            PathBuf::from("Artist/Album/01 Title.opus")
+        );
+        assert_eq!(
+            unique_name(&template, &second, "opus", &mut taken),
+            PathBuf::from(format!("Artist/Album/01 Title [{}].opus", fmt_id(2)))
+        );
+    }
+}
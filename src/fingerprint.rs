@@ -0,0 +1,106 @@
+use ffmpeg::ChannelLayout;
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+
+use std::path::Path;
+
+use crate::error::WusicError;
+use crate::transcode::Samples;
+
+// Songs are fingerprinted in mono at a low sample rate, like Haitsma and Kalker's fingerprint
+const SAMPLE_RATE: u32 = 5512;
+const FRAME: usize = 2048;
+const HOP: usize = 512;
+
+// Energy bands between 300Hz and 2000Hz, one bit for each pair of neighbouring bands
+const BANDS: usize = 33;
+const LOW: f64 = 300.0;
+const HIGH: f64 = 2000.0;
+
+// Fingerprints with fewer differing bits than this are the same song
+const MATCH_BER: f64 = 0.35;
+// How far in frames fingerprints are shifted to line up, about 5 seconds
+const MAX_OFFSET: isize = 54;
+// Fingerprints have to overlap by this many frames to be compared
+const MIN_OVERLAP: usize = 32;
+
+// Computes the fingerprint of a song, a 32 bit sub fingerprint for every frame
+pub fn compute(path: &Path) -> Result<Vec<u32>, WusicError> {
+    let samples = Samples::open(path, Some(ChannelLayout::MONO), Some(SAMPLE_RATE))?;
+    let mut mono = Vec::new();
+    samples.each(|s| mono.extend_from_slice(s))?;
+
+    let fft = FftPlanner::<f32>::new().plan_fft_forward(FRAME);
+    let window: Vec<f32> = (0..FRAME)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / FRAME as f32).cos())
+        .collect();
+
+    // Logarithmically spaced band edges, as fft bins
+    let edges: Vec<usize> = (0..=BANDS)
+        .map(|b| {
+            let freq = LOW * (HIGH / LOW).powf(b as f64 / BANDS as f64);
+            (freq * FRAME as f64 / SAMPLE_RATE as f64).round() as usize
+        })
+        .collect();
+
+    let mut fingerprint = Vec::new();
+    let mut previous: Option<Vec<f32>> = None;
+    let mut buffer = vec![Complex::default(); FRAME];
+    for start in (0..mono.len().saturating_sub(FRAME)).step_by(HOP) {
+        for (i, value) in buffer.iter_mut().enumerate() {
+            *value = Complex::new(mono[start + i] * window[i], 0.0);
+        }
+        fft.process(&mut buffer);
+
+        let energies: Vec<f32> = edges
+            .windows(2)
+            .map(|edge| {
+                buffer[edge[0]..edge[1].max(edge[0] + 1)]
+                    .iter()
+                    .map(|c| c.norm_sqr())
+                    .sum()
+            })
+            .collect();
+
+        // A bit is set when the energy difference between bands grows over time
+        if let Some(previous) = &previous {
+            let mut sub = 0u32;
+            for band in 0..BANDS - 1 {
+                let now = energies[band] - energies[band + 1];
+                let before = previous[band] - previous[band + 1];
+                if now - before > 0.0 {
+                    sub |= 1 << band;
+                }
+            }
+            fingerprint.push(sub);
+        }
+        previous = Some(energies);
+    }
+
+    Ok(fingerprint)
+}
+
+// Lowest bit error rate between two fingerprints, over the offsets that line them up
+fn bit_error_rate(a: &[u32], b: &[u32]) -> f64 {
+    let mut best = 1.0;
+    for offset in -MAX_OFFSET..=MAX_OFFSET {
+        let (a, b) = if offset < 0 {
+            (a, b.get(-offset as usize..).unwrap_or_default())
+        } else {
+            (a.get(offset as usize..).unwrap_or_default(), b)
+        };
+        let overlap = a.len().min(b.len());
+        if overlap < MIN_OVERLAP {
+            continue;
+        }
+
+        let errors: u32 = a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum();
+        best = f64::min(best, errors as f64 / (overlap * 32) as f64);
+    }
+    best
+}
+
+// Whether two fingerprints are of the same song
+pub fn matches(a: &[u32], b: &[u32]) -> bool {
+    bit_error_rate(a, b) < MATCH_BER
+}
@@ -0,0 +1,115 @@
+use serde::{Deserialize, Serialize};
+use sled::Db;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::db::{fmt_key, get_stored, parse_id, remove_stored};
+use crate::error::WusicError;
+
+// An ingest that is in progress
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    // File the song is written to first
+    pub tmp: PathBuf,
+    // id and path of the song once it is known
+    pub target: Option<(u128, PathBuf)>,
+    // id and path of the song being replaced
+    pub replaced: Option<(u128, PathBuf)>,
+}
+
+// Records an ingest step, keyed by an id of its own
+pub fn write(db: &Db, id: u128, entry: &Entry) -> Result<(), WusicError> {
+    db.open_tree("journal")?
+        .insert(id.to_be_bytes(), bincode::serialize(entry)?)?;
+    // Steps have to be on disk before the files they describe
+    db.flush()?;
+    Ok(())
+}
+
+// Marks an ingest as finished
+pub fn end(db: &Db, id: u128) -> Result<(), WusicError> {
+    db.open_tree("journal")?.remove(id.to_be_bytes())?;
+    Ok(())
+}
+
+// Finishes ingests whose record made it into the db and rolls back the rest. Ingests
+// that can't be recovered are left in the journal to try again, rather than stopping
+// everything else.
+pub fn recover(db: &Db) -> Result<(), WusicError> {
+    let journal = db.open_tree("journal")?;
+    for item in journal.iter() {
+        let (k, v) = item?;
+        let entry: Entry = match bincode::deserialize(&v) {
+            Ok(entry) => entry,
+            Err(e) => {
+                println!("{} \t| failed to read journal entry! {}", fmt_key(&k), e);
+                continue;
+            }
+        };
+
+        match recover_entry(db, &entry) {
+            Ok(()) => {
+                journal.remove(k)?;
+            }
+            Err(e) => println!(
+                "{} \t| failed to recover interrupted ingest! {}",
+                entry.tmp.display(),
+                e
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+fn recover_entry(db: &Db, entry: &Entry) -> Result<(), WusicError> {
+    let committed = match &entry.target {
+        Some((id, path)) => path.exists() && get_stored(db, *id)?.is_some(),
+        None => false,
+    };
+    if committed {
+        // Finish removing the replaced song
+        if let (Some((id, path)), Some((old_id, old_path))) = (&entry.target, &entry.replaced) {
+            if old_id != id {
+                if let Some(old) = get_stored(db, *old_id)? {
+                    remove_stored(db, &old)?;
+                }
+            }
+            if old_path != path && old_path.exists() {
+                fs::remove_file(old_path)?;
+            }
+        }
+        println!("{} \t| finished interrupted ingest", entry.tmp.display());
+    } else {
+        // Remove whatever the ingest got to write
+        if let Some((_, path)) = &entry.target {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        println!("{} \t| rolled back interrupted ingest", entry.tmp.display());
+    }
+    if entry.tmp.exists() {
+        fs::remove_file(&entry.tmp)?;
+    }
+    Ok(())
+}
+
+// Removes temporary files left in the store by crashes, which are named <id>.tmp
+pub fn clean(msp: &Path) -> Result<(), WusicError> {
+    for entry in fs::read_dir(msp)? {
+        let path = entry?.path();
+        let stale = path.extension().map(|e| e == "tmp").unwrap_or(false)
+            && path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(parse_id)
+                .is_some();
+        if stale && path.is_file() {
+            println!("{} \t| removing stale file", path.display());
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
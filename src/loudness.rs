@@ -0,0 +1,378 @@
+use ffmpeg::ChannelLayout;
+
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+use std::path::Path;
+
+use crate::error::WusicError;
+use crate::profile::Profile;
+use crate::transcode::Samples;
+
+pub use crate::schema::{Gain, Loudness};
+
+// Reference loudness of R128_* tags, and of REPLAYGAIN_* tags
+const R128_REFERENCE: f64 = -23.0;
+const REPLAYGAIN_REFERENCE: f64 = -18.0;
+
+// Gating thresholds from EBU R128 / ITU-R BS.1770
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+
+// Blocks are kept in bins this many LU wide, which is as fine as gating needs
+const BIN: f64 = 0.1;
+
+// True peak is measured on a 4x oversampled signal
+const OVERSAMPLE: usize = 4;
+const TAPS: usize = 12;
+
+// The 400ms blocks of a song above the absolute gate, binned by loudness, and its true
+// peak. Albums are gated from the bins, so ingest doesn't keep every block of every song.
+pub struct Measurement {
+    bins: Vec<Bin>,
+    peak: f64,
+}
+
+// Blocks with about the same loudness, and their summed mean square energy
+#[derive(Clone, Copy)]
+struct Bin {
+    index: u16,
+    blocks: u32,
+    energy: f64,
+}
+
+impl Measurement {
+    // Loudness of a single song, none if it is silent
+    pub fn gain(&self) -> Option<Gain> {
+        Some(Gain {
+            loudness: integrated(&self.bins)?,
+            peak: self.peak,
+        })
+    }
+
+    // Loudness of songs played back to back, like an album
+    pub fn album(measurements: &[Measurement]) -> Option<Gain> {
+        let bins: Vec<Bin> = measurements
+            .iter()
+            .flat_map(|m| m.bins.iter().copied())
+            .collect();
+        Some(Gain {
+            loudness: integrated(&bins)?,
+            peak: measurements.iter().map(|m| m.peak).fold(0.0, f64::max),
+        })
+    }
+}
+
+impl Loudness {
+    // Gain tags for a song encoded with a profile
+    pub fn tags(&self, profile: &Profile) -> Vec<(String, String)> {
+        let mut tags = Vec::new();
+        let gains = [("TRACK", Some(&self.track)), ("ALBUM", self.album.as_ref())];
+
+        // ffmpeg's mp4 muxer has no freeform atoms to put gains in, it would drop them
+        if profile.mp4() {
+            return tags;
+        }
+
+        // Opus players only read R128 gains, as Q7.8 relative to -23 LUFS
+        if profile.format == "opus" {
+            for (kind, gain) in gains {
+                if let Some(gain) = gain {
+                    let q78 = ((R128_REFERENCE - gain.loudness) * 256.0).round();
+                    tags.push((
+                        format!("R128_{}_GAIN", kind),
+                        (q78.clamp(i16::MIN as f64, i16::MAX as f64) as i16).to_string(),
+                    ));
+                }
+            }
+        } else {
+            for (kind, gain) in gains {
+                if let Some(gain) = gain {
+                    tags.push((
+                        format!("REPLAYGAIN_{}_GAIN", kind),
+                        format!("{:.2} dB", REPLAYGAIN_REFERENCE - gain.loudness),
+                    ));
+                    tags.push((
+                        format!("REPLAYGAIN_{}_PEAK", kind),
+                        format!("{:.6}", gain.peak),
+                    ));
+                }
+            }
+        }
+
+        tags
+    }
+}
+
+// Decodes a song and measures its loudness
+pub fn measure(path: &Path) -> Result<Measurement, WusicError> {
+    let samples = Samples::open(path, None, None)?;
+    let mut meter = Meter::new(samples.rate, samples.layout);
+    samples.each(|s| meter.add(s))?;
+    Ok(meter.finish())
+}
+
+// Loudness in LUFS of a mean square energy
+fn loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+// Integrated loudness in LUFS of binned blocks, none if every block was below the
+// absolute gate, like silence or songs shorter than a block
+fn integrated(bins: &[Bin]) -> Option<f64> {
+    let mean = |bins: &mut dyn Iterator<Item = &Bin>| {
+        let (energy, blocks) = bins.fold((0.0, 0), |(energy, blocks), bin| {
+            (energy + bin.energy, blocks + bin.blocks)
+        });
+        if blocks > 0 {
+            Some(energy / blocks as f64)
+        } else {
+            None
+        }
+    };
+
+    let absolute = mean(&mut bins.iter())?;
+    let gate = loudness(absolute) + RELATIVE_GATE;
+    // Bins are gated by their middle, blocks are at most half a bin off from it
+    let energy = mean(
+        &mut bins
+            .iter()
+            .filter(|bin| ABSOLUTE_GATE + (bin.index as f64 + 0.5) * BIN > gate),
+    );
+    Some(loudness(energy.unwrap_or(absolute)))
+}
+
+// Second order IIR filter
+#[derive(Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+// K-weighting filters for a sample rate, a high shelf and then a high pass
+fn k_weighting(rate: f64) -> [Biquad; 2] {
+    let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    [shelf, pass]
+}
+
+// Weight of each channel of a layout in the loudness sum, surround channels count more
+// and LFE not at all
+fn channel_weights(layout: ChannelLayout) -> Vec<f64> {
+    let surround = ChannelLayout::SIDE_LEFT
+        | ChannelLayout::SIDE_RIGHT
+        | ChannelLayout::BACK_LEFT
+        | ChannelLayout::BACK_RIGHT;
+    let lfe = ChannelLayout::LOW_FREQUENCY | ChannelLayout::LOW_FREQUENCY_2;
+
+    // Channels are interleaved in the order of their bits
+    (0..64)
+        .map(|bit| ChannelLayout::from_bits_truncate(1 << bit))
+        .filter(|channel| !channel.is_empty() && layout.contains(*channel))
+        .map(|channel| {
+            if lfe.contains(channel) {
+                0.0
+            } else if surround.contains(channel) {
+                1.41
+            } else {
+                1.0
+            }
+        })
+        .collect()
+}
+
+// Accumulates interleaved samples into 100ms energies and a true peak
+struct Meter {
+    channels: usize,
+    weights: Vec<f64>,
+    filters: Vec<[Biquad; 2]>,
+    history: Vec<[f64; TAPS]>,
+    interpolation: [[f64; TAPS]; OVERSAMPLE],
+
+    step: usize,
+    energy: f64,
+    samples: usize,
+    steps: Vec<f64>,
+    peak: f64,
+}
+
+impl Meter {
+    fn new(rate: u32, layout: ChannelLayout) -> Meter {
+        let weights = channel_weights(layout);
+        let channels = weights.len();
+        // Windowed sinc taps for each oversampled phase
+        let mut interpolation = [[0.0; TAPS]; OVERSAMPLE];
+        for (phase, taps) in interpolation.iter_mut().enumerate() {
+            for (k, tap) in taps.iter_mut().enumerate() {
+                let t = (TAPS / 2 - 1) as f64 + phase as f64 / OVERSAMPLE as f64 - k as f64;
+                let sinc = if t == 0.0 {
+                    1.0
+                } else {
+                    (PI * t).sin() / (PI * t)
+                };
+                let window = 0.5 * (1.0 + (PI * t / (TAPS / 2) as f64).cos());
+                *tap = sinc * window;
+            }
+        }
+
+        Meter {
+            channels,
+            weights,
+            filters: vec![k_weighting(rate as f64); channels],
+            history: vec![[0.0; TAPS]; channels],
+            interpolation,
+
+            step: (rate as usize / 10).max(1),
+            energy: 0.0,
+            samples: 0,
+            steps: Vec::new(),
+            peak: 0.0,
+        }
+    }
+
+    // Adds interleaved samples
+    fn add(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.channels) {
+            for (channel, sample) in frame.iter().enumerate() {
+                let x = *sample as f64;
+
+                let [shelf, pass] = &mut self.filters[channel];
+                let y = pass.process(shelf.process(x));
+                self.energy += self.weights[channel] * y * y;
+
+                let history = &mut self.history[channel];
+                history.rotate_left(1);
+                history[TAPS - 1] = x;
+                for taps in &self.interpolation {
+                    let interpolated: f64 =
+                        taps.iter().zip(history.iter()).map(|(t, h)| t * h).sum();
+                    self.peak = self.peak.max(interpolated.abs());
+                }
+            }
+
+            self.samples += 1;
+            if self.samples == self.step {
+                self.steps.push(self.energy / self.step as f64);
+                self.energy = 0.0;
+                self.samples = 0;
+            }
+        }
+    }
+
+    // Overlapping 400ms blocks, every 100ms
+    fn finish(self) -> Measurement {
+        let mut bins = BTreeMap::new();
+        for energy in self.steps.windows(4).map(|w| w.iter().sum::<f64>() / 4.0) {
+            let level = loudness(energy);
+            if level > ABSOLUTE_GATE {
+                let index = ((level - ABSOLUTE_GATE) / BIN) as u16;
+                let bin = bins.entry(index).or_insert(Bin {
+                    index,
+                    blocks: 0,
+                    energy: 0.0,
+                });
+                bin.blocks += 1;
+                bin.energy += energy;
+            }
+        }
+
+        Measurement {
+            bins: bins.into_values().collect(),
+            peak: self.peak,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 997Hz sine at a level in dBFS in both channels, which BS.1770 reads as that many LUFS
+    fn sine(level: f64, seconds: usize) -> Measurement {
+        let rate = 48000;
+        let amplitude = 10f64.powf(level / 20.0);
+        let samples: Vec<f32> = (0..rate as usize * seconds)
+            .flat_map(|i| {
+                let s = amplitude * (2.0 * PI * 997.0 * i as f64 / rate as f64).sin();
+                [s as f32, s as f32]
+            })
+            .collect();
+        let mut meter = Meter::new(rate, ChannelLayout::STEREO);
+        meter.add(&samples);
+        meter.finish()
+    }
+
+    #[test]
+    fn sine_loudness() {
+        for level in [-23.0, -33.0, -3.0] {
+            let gain = sine(level, 10).gain().unwrap();
+            assert!(
+                (gain.loudness - level).abs() < 0.1,
+                "{} dBFS read as {} LUFS",
+                level,
+                gain.loudness
+            );
+            let amplitude = 10f64.powf(level / 20.0);
+            assert!((gain.peak / amplitude - 1.0).abs() < 0.02);
+        }
+    }
+
+    #[test]
+    fn surround_weights() {
+        assert_eq!(
+            channel_weights(ChannelLayout::_5POINT1),
+            [1.0, 1.0, 1.0, 0.0, 1.41, 1.41]
+        );
+        // No LFE, the fourth channel is a surround one
+        assert_eq!(
+            channel_weights(ChannelLayout::_5POINT0),
+            [1.0, 1.0, 1.0, 1.41, 1.41]
+        );
+        assert_eq!(
+            channel_weights(ChannelLayout::_7POINT1),
+            [1.0, 1.0, 1.0, 0.0, 1.41, 1.41, 1.41, 1.41]
+        );
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        let mut meter = Meter::new(48000, ChannelLayout::STEREO);
+        meter.add(&vec![0.0; 48000 * 2 * 5]);
+        assert!(meter.finish().gain().is_none());
+
+        // Shorter than a single block
+        let short = sine(-23.0, 0);
+        assert!(short.gain().is_none());
+    }
+}
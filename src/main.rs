@@ -1,31 +1,167 @@
 extern crate ffmpeg_next as ffmpeg;
 
-use bincode;
+mod ann;
+mod art;
+mod db;
+mod error;
+mod export;
+mod fingerprint;
+mod journal;
+mod loudness;
+mod profile;
+mod query;
+mod schema;
+mod store;
+mod tags;
+mod transcode;
+
 use bliss_audio::distance::cosine_distance;
 use bliss_audio::{Analysis, AnalysisIndex, Song};
-use clap::{Arg, Command};
+use clap::{Arg, ArgGroup, ArgMatches, Command};
 use half::f16;
-use rust_fuzzy_search::fuzzy_compare;
-use serde::{Deserialize, Serialize};
+use rayon::prelude::*;
+use serde::Serialize;
 use serde_json;
 use sled::{self, Db};
 use walkdir::{self, WalkDir};
 
-use std::path::Path;
+use crate::art::{art_path, Picture};
+use crate::db::{
+    ensure_indexes, find_by_title, find_closest_song, find_closest_songs, fmt_id, fmt_key,
+    get_stored, insert_stored, iter_stored, legacy_id, lookup, lookup_phash, new_id, parse_id,
+    rebuild_indexes, remove_stored, Stored, INDEXES,
+};
+use crate::error::WusicError;
+use crate::export::{Manifest, Mode, Template, DEFAULT_TEMPLATE};
+use crate::loudness::{Loudness, Measurement};
+use crate::profile::{Profile, Profiles};
+use crate::query::Query;
+use crate::schema::{decode, encode};
+use crate::store::{Layout, Store};
+use crate::tags::Tags;
+use crate::transcode::{remux, transcode};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::{env, error::Error};
 use std::{fs, io};
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Stored {
-    fhash: [u8; 32],
-    phash: u128,
+// Songs analyzed in parallel before they are ingested one by one
+const ANALYSIS_BATCH: usize = 256;
+
+// Files that come along with albums, which ingest passes over without trying them
+const NOT_AUDIO: [&str; 16] = [
+    "jpg", "jpeg", "png", "gif", "bmp", "webp", "cue", "log", "txt", "nfo", "m3u", "m3u8", "pdf",
+    "md5", "sfv", "accurip",
+];
+
+// How non-interactive ingest decides what to do with a song
+struct Policy {
+    action: char,
+    skip_threshold: f32,
+    // How close a song has to be to a stored one to replace it
+    replace_threshold: f32,
+    // How similar a title has to be to replace the song with it
+    title_threshold: f32,
+}
+
+// Options for ingesting songs
+struct IngestOptions {
+    copy: bool,
+    profile_name: String,
+    profile: Profile,
+    policy: Option<Policy>,
+}
 
-    title: String,
-    artist: String,
-    album: String,
+// A change sync makes to a stored song, with its old and new record
+enum SyncChange {
+    Update(Stored, Stored),
+    // The audio changed
+    Reanalyze(Stored, Stored),
+    Remove(Stored),
+}
 
-    analysis: Analysis,
+// Songs that album gain is computed over
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum AlbumGroup {
+    // Normalized album artist and album
+    Tagged(String, String),
+    // Source directory of songs without an album
+    Directory(PathBuf),
+}
+
+impl AlbumGroup {
+    fn of(tags: &Tags, path: &Path) -> AlbumGroup {
+        match tags.album_key() {
+            Some((artist, album)) => AlbumGroup::Tagged(artist, album),
+            None => AlbumGroup::Directory(path.parent().map(Path::to_path_buf).unwrap_or_default()),
+        }
+    }
+}
+
+impl fmt::Display for AlbumGroup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AlbumGroup::Tagged(artist, album) => write!(f, "{} - {}", artist, album),
+            AlbumGroup::Directory(dir) => write!(f, "{}", dir.display()),
+        }
+    }
+}
+
+// A song that was ingested, for album gain
+struct Ingested {
+    id: u128,
+    group: AlbumGroup,
+    measurement: Measurement,
+}
+
+// A song decoded for everything ingest needs, done in parallel ahead of it
+struct Analyzed {
+    song: Song,
+    fingerprint: Vec<u32>,
+    measurement: Measurement,
+}
+
+impl Analyzed {
+    fn new(path: &Path) -> Result<Analyzed, WusicError> {
+        Ok(Analyzed {
+            song: Song::new(path)?,
+            fingerprint: fingerprint::compute(path)?,
+            measurement: loudness::measure(path)?,
+        })
+    }
+}
+
+// What ingest did with a song
+enum Outcome {
+    Ingested(Ingested),
+    Skipped,
+    // Stop ingesting, what was ingested so far still gets album gain
+    Aborted,
+}
+
+// A single ingest decision, written to the report
+#[derive(Serialize)]
+struct Decision<'a> {
+    path: &'a str,
+    // Only songs that were stored have an id
+    id: Option<String>,
+    phash: String,
+
+    title: &'a str,
+    artist: &'a str,
+    album: &'a str,
+
+    closest: String,
+    closest_dist: f32,
+    tclosest: String,
+    tclosest_dist: f32,
+
+    action: &'a str,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -46,8 +182,56 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .arg(
                     Arg::new("copy")
                         .long("copy")
-                        .help("Copy songs instead of transcoding (Make sure they are in the correct format first (Opus 160k 48k))")
+                        .help("Copy songs instead of transcoding (Make sure they are in the format of the profile first)")
                         .takes_value(false),
+                )
+                .arg(
+                    Arg::new("profile")
+                        .long("profile")
+                        .help("Encoding profile to use (Defaults to the config's default).")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("yes")
+                        .long("yes")
+                        .short('y')
+                        .help("Don't prompt, decide using the policy.")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::new("policy")
+                        .long("policy")
+                        .help("What to do with songs that aren't skipped (implies --yes).")
+                        .takes_value(true)
+                        .possible_values(["new", "replace", "replace-title", "skip"])
+                        .default_value("new"),
+                )
+                .arg(
+                    Arg::new("skip-threshold")
+                        .long("skip-threshold")
+                        .help("Skip songs closer than this distance to a stored song when not prompting.")
+                        .takes_value(true)
+                        .default_value("0.05"),
+                )
+                .arg(
+                    Arg::new("replace-threshold")
+                        .long("replace-threshold")
+                        .help("Only replace stored songs closer than this distance when not prompting, otherwise add as new.")
+                        .takes_value(true)
+                        .default_value("0.05"),
+                )
+                .arg(
+                    Arg::new("title-threshold")
+                        .long("title-threshold")
+                        .help("Only replace stored songs with a title at least this similar (0 to 1) when using replace-title.")
+                        .takes_value(true)
+                        .default_value("0.8"),
+                )
+                .arg(
+                    Arg::new("report")
+                        .long("report")
+                        .help("Write every ingest decision to this file (JSON lines).")
+                        .takes_value(true),
                 ),
         )
         .subcommand(
@@ -58,9 +242,286 @@ fn main() -> Result<(), Box<dyn Error>> {
                         .long("detailed")
                         .help("List song analysis information.")
                         .takes_value(false),
+                )
+                .arg(
+                    Arg::new("artist")
+                        .long("artist")
+                        .help("Only list songs by this artist.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("album")
+                        .long("album")
+                        .help("Only list songs from this album.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("title")
+                        .long("title")
+                        .help("Only list songs with this title.")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            Command::new("sync")
+                .about("Syncs database with the store")
+                .arg(
+                    Arg::new("orphans")
+                        .long("orphans")
+                        .help("What to do with files in the store that are not in the database.")
+                        .possible_values(["ask", "adopt", "quarantine", "ignore"])
+                        .default_value("ask"),
+                )
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .help("Only print what would be changed.")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::new("max-delete")
+                        .long("max-delete")
+                        .help("Refuse to remove more than this percent of songs.")
+                        .takes_value(true)
+                        .default_value("10"),
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .help("Remove songs even if there are more than --max-delete.")
+                        .takes_value(false),
+                ),
+        )
+        .subcommand(
+            Command::new("art")
+                .about("Manage the art of a song")
+                .subcommand_required(true)
+                .arg(
+                    Arg::new("id")
+                        .long("id")
+                        .alias("phash")
+                        .help("id (or phash) of the song.")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .subcommand(
+                    Command::new("extract").about("Save the art of a song").arg(
+                        Arg::new("output")
+                            .long("output")
+                            .short('o')
+                            .help("Path to save the art to.")
+                            .takes_value(true)
+                            .required(true),
+                    ),
+                )
+                .subcommand(
+                    Command::new("set").about("Replace the art of a song").arg(
+                        Arg::new("image")
+                            .long("image")
+                            .help("Path to the new art (jpg or png).")
+                            .takes_value(true)
+                            .required(true),
+                    ),
+                )
+                .subcommand(Command::new("remove").about("Remove the art of a song")),
+        )
+        .subcommand(
+            Command::new("migrate")
+                .about("Upgrade songs in the database to the current version, giving them ids"),
+        )
+        .subcommand(
+            Command::new("regain")
+                .about("Recompute the loudness and album gain of an album")
+                .arg(
+                    Arg::new("album")
+                        .long("album")
+                        .help("Album to recompute.")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("export")
+                .about("Mirror the store into a browsable tree of named files")
+                .arg(
+                    Arg::new("path")
+                        .long("path")
+                        .help("Directory to export to.")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("template")
+                        .long("template")
+                        .help("How to name songs, from {title} {artist} {album} {albumartist} {track} {disc} {date} {genre} {composer} {id}.")
+                        .takes_value(true)
+                        .default_value(DEFAULT_TEMPLATE),
+                )
+                .arg(
+                    Arg::new("mode")
+                        .long("mode")
+                        .help("How songs are put into the export.")
+                        .takes_value(true)
+                        .possible_values(Mode::NAMES)
+                        .default_value("symlink"),
+                ),
+        )
+        .subcommand(
+            Command::new("push")
+                .about("Mirror part of the library onto a device")
+                .arg(
+                    Arg::new("path")
+                        .long("path")
+                        .help("Directory the device is mounted at.")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("artist")
+                        .long("artist")
+                        .help("Only push songs by this artist.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("album")
+                        .long("album")
+                        .help("Only push songs from this album.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("title")
+                        .long("title")
+                        .help("Only push songs with this title.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("playlist")
+                        .long("playlist")
+                        .help("Only push songs in this playlist (.m3u or .m3u8).")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("query")
+                        .long("query")
+                        .help("Only push songs matching this search, like \"pink floyd album:the wall\".")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("profile")
+                        .long("profile")
+                        .help("Encoding profile to transcode songs to (Defaults to copying them).")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("template")
+                        .long("template")
+                        .help("How to name songs, like for export.")
+                        .takes_value(true)
+                        .default_value(DEFAULT_TEMPLATE),
+                )
+                .arg(
+                    Arg::new("init")
+                        .long("init")
+                        .help("Push to a device that hasn't been pushed to before.")
+                        .takes_value(false),
+                ),
+        )
+        .subcommand(
+            Command::new("layout")
+                .about("Show or change how songs are laid out in the store")
+                .arg(
+                    Arg::new("layout")
+                        .help("Layout to move the songs to.")
+                        .possible_values(Layout::NAMES),
+                ),
+        )
+        .subcommand(
+            Command::new("index")
+                .about("Manage the database indexes")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("rebuild").about("Rebuild all indexes from the stored songs"),
+                ),
+        )
+        .subcommand(
+            Command::new("playlist")
+                .about("Generate a playlist of similar songs")
+                .arg(
+                    Arg::new("id")
+                        .long("id")
+                        .alias("phash")
+                        .help("id (or phash) of the song to start from.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("title")
+                        .long("title")
+                        .help("Title of the song to start from.")
+                        .takes_value(true),
+                )
+                .group(
+                    ArgGroup::new("seed")
+                        .args(&["id", "title"])
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("length")
+                        .long("length")
+                        .short('n')
+                        .help("Number of songs in the playlist.")
+                        .takes_value(true)
+                        .default_value("20"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .short('o')
+                        .help("Path to write the playlist to (.m3u or .m3u8), defaults to stdout.")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            Command::new("similar")
+                .about("Find songs similar to a song")
+                .arg(
+                    Arg::new("id")
+                        .long("id")
+                        .alias("phash")
+                        .help("id (or phash) of a stored song.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("title")
+                        .long("title")
+                        .help("Title of a stored song.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("file")
+                        .long("file")
+                        .help("Path to a song outside of the store.")
+                        .takes_value(true),
+                )
+                .group(
+                    ArgGroup::new("seed")
+                        .args(&["id", "title", "file"])
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("count")
+                        .long("count")
+                        .short('n')
+                        .help("Number of songs to list.")
+                        .takes_value(true)
+                        .default_value("10"),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .help("Output as json.")
+                        .takes_value(false),
                 ),
         )
-        .subcommand(Command::new("sync").about("Syncs database with the store"))
         .arg(
             Arg::new("db")
                 .long("db")
@@ -75,390 +536,1337 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .takes_value(true)
                 .required(true),
         )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .help("Path to encoding profiles config (Defaults to profiles.json in the store).")
+                .takes_value(true),
+        )
         .get_matches();
 
     // Open database
     let db = sled::open(matches.value_of("db").unwrap())?;
+    ensure_indexes(&db)?;
+
+    // Songs stored under their phash have to be given ids before anything else
+    let meta = db.open_tree("meta")?;
+    if meta.get("ids")?.is_none() {
+        if db.is_empty() {
+            meta.insert("ids", b"")?;
+        } else if matches.subcommand_matches("migrate").is_none() {
+            return Err("songs in db have no ids! Run migrate first".into());
+        }
+    }
 
     // Create music store directory
     let msp = Path::new(matches.value_of("store").unwrap());
     fs::create_dir_all(msp)?;
 
+    // Recover from ingests that were interrupted
+    journal::recover(&db)?;
+    journal::clean(msp)?;
+
+    // Load encoding profiles
+    let profiles = match matches.value_of("config") {
+        Some(config) => Profiles::load(Path::new(config))?,
+        None => Profiles::load(&msp.join("profiles.json"))?,
+    };
+    let store = Store {
+        path: fs::canonicalize(msp)?,
+        layout: Layout::load(&db)?,
+        profiles,
+    };
+
     ffmpeg::init().unwrap();
 
     // Handle subcommands
+    let mut failed: Vec<(String, WusicError)> = Vec::new();
     if let Some(sub_m) = matches.subcommand_matches("ingest") {
-        WalkDir::new(sub_m.value_of("path").unwrap())
+        // Non-interactive policy
+        let policy = if sub_m.is_present("yes") || sub_m.occurrences_of("policy") > 0 {
+            Some(Policy {
+                action: match sub_m.value_of("policy").unwrap() {
+                    "new" => 'n',
+                    "replace" => 'r',
+                    "replace-title" => 't',
+                    _ => 's',
+                },
+                skip_threshold: sub_m.value_of_t("skip-threshold")?,
+                replace_threshold: sub_m.value_of_t("replace-threshold")?,
+                title_threshold: sub_m.value_of_t("title-threshold")?,
+            })
+        } else {
+            None
+        };
+        let profile_name = sub_m
+            .value_of("profile")
+            .unwrap_or(&store.profiles.default)
+            .to_owned();
+        let options = IngestOptions {
+            copy: sub_m.is_present("copy"),
+            profile: store.profiles.profile(&profile_name)?.clone(),
+            profile_name,
+            policy,
+        };
+        let mut report = match sub_m.value_of("report") {
+            Some(p) => Some(fs::File::create(p)?),
+            None => None,
+        };
+
+        // Find songs to ingest
+        let files: Vec<PathBuf> = WalkDir::new(sub_m.value_of("path").unwrap())
             .into_iter()
             .filter_map(|file| file.ok())
-            .filter(|file| file.metadata().unwrap().is_file())
-            .for_each(|file| {
-                // Load song
-                let path = file.path();
-                let mut song = Song::new(path).unwrap();
-                let format = ffmpeg::format::input(&path).unwrap();
-
-                // Get current metadata
-                let mut title = String::default();
-                let mut artist = String::default();
-                let mut album = String::default();
-                if let Some(t) = format.stream(0).unwrap().metadata().get("title") {
-                    title = t.to_owned();
-                }
-                if let Some(a) = format.stream(0).unwrap().metadata().get("artist") {
-                    artist = a.to_owned();
-                }
-                if let Some(a) = format.stream(0).unwrap().metadata().get("album") {
-                    album = a.to_owned();
-                }
+            .filter(|file| file.file_type().is_file())
+            .map(|file| file.into_path())
+            .filter(|path| {
+                !path
+                    .extension()
+                    .and_then(|e| e.to_str())
+                    .map(|e| NOT_AUDIO.contains(&e.to_lowercase().as_str()))
+                    .unwrap_or(false)
+            })
+            .collect();
 
-                // perceptually hash song
-                let mut phash = gen_phash(&song.analysis);
-                if let Some(v) = db.get(phash.to_be_bytes()).unwrap() {
-                    let stored: Stored = bincode::deserialize(&v).unwrap();
-
-                    println!("HASH COLLISION!!! Assuming that its an dupe! Skipping...");
-                    println!("--- Prev Song ---");
-                    println!("{} - {}\t| {}", stored.artist, stored.title, stored.album);
-                    println!("--- Curr Song ---");
-                    println!("{} - {}\t| {}", artist, title, album);
-                    println!("-----------------\n")
-                } else {
-                    // Start ingesting
-                    println!("\n--- Curr Song ---");
-                    println!(
-                        "{} - {}\t| {}: {:x}_{:x}",
-                        artist,
-                        title,
-                        album,
-                        phash >> 32,
-                        (phash << 96) >> 96
+        // Analyze songs in parallel a batch at a time, so only a batch of analyses is
+        // ever held in memory
+        let total = files.len();
+        let analyzed = AtomicUsize::new(0);
+        let mut albums: BTreeMap<AlbumGroup, HashMap<u128, Measurement>> = BTreeMap::new();
+        'ingest: for batch in files.chunks(ANALYSIS_BATCH) {
+            let songs: Vec<(&PathBuf, Option<Result<Analyzed, WusicError>>)> = batch
+                .par_iter()
+                .map(|path| {
+                    let song = has_audio(path).then(|| Analyzed::new(path));
+                    eprint!(
+                        "\rAnalyzing songs [{}/{}]",
+                        analyzed.fetch_add(1, Ordering::Relaxed) + 1,
+                        total
                     );
-                    // get closest song
-                    let (closest, closest_dist, tclosest, tclosest_dist) =
-                        find_closest_song(&db, &title, &song.analysis);
-                    if tclosest != 0 {
-                        let tclosest_stored: Stored =
-                            bincode::deserialize(&db.get(tclosest.to_be_bytes()).unwrap().unwrap())
-                                .unwrap();
-                        println!("--- Closest Title (Dist: {}) ---", tclosest_dist);
-                        println!(
-                            "{} - {}\t| {}: {:x}_{:x}",
-                            tclosest_stored.artist,
-                            tclosest_stored.title,
-                            tclosest_stored.album,
-                            tclosest >> 32,
-                            (tclosest << 96) >> 96
-                        );
+                    (path, song)
+                })
+                .collect();
+            eprintln!();
+
+            // Ingest analyzed songs
+            for (path, song) in songs {
+                // Not counted as a failure, albums come with all kinds of files
+                let song = match song {
+                    Some(song) => song,
+                    None => {
+                        println!("{} \t| skipped, no audio", path.display());
+                        continue;
                     }
-                    if closest != 0 {
-                        let closest_stored: Stored =
-                            bincode::deserialize(&db.get(closest.to_be_bytes()).unwrap().unwrap())
-                                .unwrap();
-                        println!("--- Closest Song (Dist: {}) ---", closest_dist);
-                        println!(
-                            "{} - {}\t| {}: {:x}_{:x}",
-                            closest_stored.artist,
-                            closest_stored.title,
-                            closest_stored.album,
-                            closest >> 32,
-                            (closest << 96) >> 96
-                        );
-                        // skip if closer that 0.0001
-                        if closest_dist < 0.0001 {
-                            println!("phash Already in DB so Skipping!");
-                            return;
-                        }
+                };
+                match ingest_song(&db, &store, path, song, &options, &mut report) {
+                    Ok(Outcome::Ingested(ingested)) => {
+                        albums
+                            .entry(ingested.group)
+                            .or_default()
+                            .insert(ingested.id, ingested.measurement);
                     }
-                    println!("-----------------");
-
-                    let process = requestty::prompt_one(
-                        requestty::Question::expand("process")
-                            .message("Choose process")
-                            .choices(vec![
-                                ('n', "New"),
-                                ('r', "Replace closest"),
-                                ('t', "Replace closest title"),
-                                ('s', "Skip"),
-                                ('x', "Abort"),
-                            ])
-                            .default('n')
-                            .build(),
-                    )
-                    .unwrap();
-
-                    if process.as_expand_item().unwrap().key == 'n' {
-                        // Get new metadata
-                        title = requestty::prompt_one(
-                            requestty::Question::input("title")
-                                .message("Song title")
-                                .default(&title)
-                                .build(),
-                        )
-                        .unwrap()
-                        .as_string()
-                        .unwrap()
-                        .to_owned();
-                        artist = requestty::prompt_one(
-                            requestty::Question::input("artist")
-                                .message("Song artist")
-                                .default(&artist)
-                                .build(),
-                        )
-                        .unwrap()
-                        .as_string()
-                        .unwrap()
-                        .to_owned();
-                        album = requestty::prompt_one(
-                            requestty::Question::input("album")
-                                .message("Song album")
-                                .default(&album)
-                                .build(),
-                        )
-                        .unwrap()
-                        .as_string()
-                        .unwrap()
-                        .to_owned();
-
-                        let mut new_path =
-                            msp.join(format!("{:x}_{:x}.opus", phash >> 32, (phash << 96) >> 96));
-
-                        if sub_m.is_present("copy") {
-                            // Copy over file
-                            process::Command::new("ffmpeg")
-                                .arg("-i")
-                                .arg(&path.to_str().unwrap())
-                                .arg("-map_metadata")
-                                .arg("-1")
-                                .arg("-metadata")
-                                .arg(format!("TITLE={}", title))
-                                .arg("-metadata")
-                                .arg(format!("ARTIST={}", artist))
-                                .arg("-metadata")
-                                .arg(format!("ALBUM={}", album))
-                                .arg("-f")
-                                .arg("opus")
-                                .arg("-c:a")
-                                .arg("copy")
-                                .arg("-vn")
-                                .arg("-hide_banner")
-                                .arg("-loglevel")
-                                .arg("error")
-                                .arg(&new_path.to_str().unwrap())
-                                .spawn()
-                                .unwrap()
-                                .wait()
-                                .unwrap();
-                        } else {
-                            let tmp_path = msp.join(format!(
-                                "{:x}_{:x}.tmp",
-                                phash >> 32,
-                                (phash << 96) >> 96
-                            ));
-                            // Transcode over file
-                            process::Command::new("ffmpeg")
-                                .arg("-i")
-                                .arg(&path.to_str().unwrap())
-                                .arg("-map_metadata")
-                                .arg("-1")
-                                .arg("-metadata")
-                                .arg(format!("TITLE={}", title))
-                                .arg("-metadata")
-                                .arg(format!("ARTIST={}", artist))
-                                .arg("-metadata")
-                                .arg(format!("ALBUM={}", album))
-                                .arg("-f")
-                                .arg("opus")
-                                .arg("-c:a")
-                                .arg("libopus")
-                                .arg("-b:a")
-                                .arg("160k")
-                                .arg("-ar")
-                                .arg("48k")
-                                .arg("-vn")
-                                .arg("-hide_banner")
-                                .arg("-loglevel")
-                                .arg("error")
-                                .arg(&tmp_path.to_str().unwrap())
-                                .spawn()
-                                .unwrap()
-                                .wait()
-                                .unwrap();
-                            // Recalculate perceptual hash
-                            song = Song::new(&tmp_path.to_str().unwrap()).unwrap();
-                            phash = gen_phash(&song.analysis);
-                            new_path = msp.join(format!(
-                                "{:x}_{:x}.opus",
-                                phash >> 32,
-                                (phash << 96) >> 96
-                            ));
-                            println!("New phash: {:x}_{:x}", phash >> 32, (phash << 96) >> 96);
-                            // Move tmp file over to correct position
-                            fs::rename(&tmp_path, &new_path).unwrap();
-                        }
-
-                        // r128gain song
-                        process::Command::new("r128gain")
-                            .arg("-v")
-                            .arg("warning")
-                            .arg(&new_path.to_str().unwrap())
-                            .spawn()
-                            .unwrap()
-                            .wait()
-                            .unwrap();
-
-                        // Hash file
-                        let mut file = fs::File::open(&new_path).unwrap();
-                        let mut hasher = blake3::Hasher::new();
-                        io::copy(&mut file, &mut hasher).unwrap();
-                        let fhash = hasher.finalize().as_bytes().to_owned();
-
-                        // Insert into db
-                        db.insert(
-                            phash.to_be_bytes(),
-                            bincode::serialize(&Stored {
-                                fhash,
-                                phash,
-
-                                title,
-                                artist,
-                                album,
-
-                                analysis: song.analysis,
-                            })
-                            .unwrap(),
-                        )
-                        .unwrap();
-                    } else if process.as_expand_item().unwrap().key == 'r' {
-                    } else if process.as_expand_item().unwrap().key == 't' {
-                    } else if process.as_expand_item().unwrap().key == 's' {
-                    } else if process.as_expand_item().unwrap().key == 'x' {
-                        db.flush().unwrap();
-                        std::process::exit(0);
-                    } else {
-                        panic!("process not defined!");
+                    Ok(Outcome::Skipped) => {}
+                    Ok(Outcome::Aborted) => break 'ingest,
+                    Err(e) => {
+                        println!("{} \t| failed to ingest! {}", path.display(), e);
+                        failed.push((path.display().to_string(), e));
+                        // Roll back what the failed ingest left behind
+                        journal::recover(&db)?;
                     }
                 }
-            });
+            }
+        }
+
+        // Write album gain now that the albums are complete
+        for (group, measured) in albums {
+            if let Err(e) = album_gain(&db, &store, &group, measured) {
+                println!("{} \t| failed to compute album gain! {}", group, e);
+                failed.push((group.to_string(), e));
+            }
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("regain") {
+        // Songs of different artists can share an album name
+        let mut groups = BTreeSet::new();
+        for id in lookup(&db, "album", sub_m.value_of("album").unwrap())? {
+            if let Some(stored) = get_stored(&db, id)? {
+                if let Some((artist, album)) = stored.tags.album_key() {
+                    groups.insert(AlbumGroup::Tagged(artist, album));
+                }
+            }
+        }
+        if groups.is_empty() {
+            return Err("album not in db!".into());
+        }
+
+        for group in groups {
+            if let Err(e) = album_gain(&db, &store, &group, HashMap::new()) {
+                println!("{} \t| failed to compute album gain! {}", group, e);
+                failed.push((group.to_string(), e));
+            }
+        }
     } else if let Some(sub_m) = matches.subcommand_matches("list") {
-        db.iter().filter_map(|f| f.ok()).for_each(|(_, v)| {
-            let stored: Stored = bincode::deserialize(&v).unwrap();
+        // Narrow down songs using the indexes
+        let songs: Box<dyn Iterator<Item = Result<Stored, WusicError>> + '_> =
+            match lookup_tags(&db, sub_m)? {
+                Some(ids) => Box::new(
+                    ids.into_iter()
+                        .filter_map(|id| get_stored(&db, id).transpose()),
+                ),
+                None => Box::new(iter_stored(&db)),
+            };
+
+        for stored in songs {
+            let stored = stored?;
             if sub_m.is_present("detailed") {
-                println!("{}", serde_json::to_string(&stored).unwrap());
+                println!("{}", serde_json::to_string(&stored)?);
             } else {
                 println!(
-                    "{:x}_{:x} | {} - {}\t| {}",
-                    stored.phash >> 32,
-                    (stored.phash << 96) >> 96,
-                    stored.artist,
-                    stored.title,
-                    stored.album
+                    "{} | {} - {}\t| {}",
+                    fmt_id(stored.id),
+                    stored.tags.artist,
+                    stored.tags.title,
+                    stored.tags.album
+                );
+            }
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("similar") {
+        // Get song to compare against
+        let (seed, analysis) = if let Some(id) = sub_m.value_of("id") {
+            let stored = find_by_id(&db, id)?.ok_or("id not in db!")?;
+            (Some(stored.id), stored.analysis)
+        } else if let Some(t) = sub_m.value_of("title") {
+            let stored = find_by_title(&db, t)?.ok_or("no songs in db!")?;
+            // Show which song the title matched, unless the output is for a program
+            if !sub_m.is_present("json") {
+                println!(
+                    "{} | {} - {}\t| {}",
+                    fmt_id(stored.id),
+                    stored.tags.artist,
+                    stored.tags.title,
+                    stored.tags.album
+                );
+            }
+            (Some(stored.id), stored.analysis)
+        } else {
+            (None, Song::new(sub_m.value_of("file").unwrap())?.analysis)
+        };
+
+        let closest = find_closest_songs(&db, &analysis, seed, sub_m.value_of_t("count")?)?;
+        if sub_m.is_present("json") {
+            let closest: Vec<_> = closest
+                .iter()
+                .map(|(dist, stored)| {
+                    serde_json::json!({
+                        "id": fmt_id(stored.id),
+                        "dist": dist,
+                        "title": stored.tags.title,
+                        "artist": stored.tags.artist,
+                        "album": stored.tags.album,
+                    })
+                })
+                .collect();
+            println!("{}", serde_json::to_string(&closest)?);
+        } else {
+            for (dist, stored) in closest {
+                println!(
+                    "{:.6} | {} | {} - {}\t| {}",
+                    dist,
+                    fmt_id(stored.id),
+                    stored.tags.artist,
+                    stored.tags.title,
+                    stored.tags.album
                 );
             }
-        });
-    } else if let Some(_) = matches.subcommand_matches("sync") {
-        db.iter().filter_map(|f| f.ok()).for_each(|(_, v)| {
-            let stored: Stored = bincode::deserialize(&v).unwrap();
-
-            let path = msp.join(format!(
-                "{:x}_{:x}.opus",
-                stored.phash >> 32,
-                (stored.phash << 96) >> 96
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("playlist") {
+        // Get song to start from
+        let seed = if let Some(id) = sub_m.value_of("id") {
+            find_by_id(&db, id)?.ok_or("id not in db!")?
+        } else {
+            find_by_title(&db, sub_m.value_of("title").unwrap())?.ok_or("no songs in db!")?
+        };
+
+        // Walk to the nearest unused song each step
+        let mut pool = Vec::new();
+        for stored in iter_stored(&db) {
+            let stored = stored?;
+            if stored.id != seed.id {
+                pool.push(stored);
+            }
+        }
+        let length: usize = sub_m.value_of_t("length")?;
+        let mut playlist = vec![seed];
+        while playlist.len() < length && !pool.is_empty() {
+            let current = &playlist[playlist.len() - 1].analysis;
+            let mut next = 0;
+            let mut next_dist = f32::MAX;
+            for (i, stored) in pool.iter().enumerate() {
+                let dist = stored.analysis.custom_distance(current, cosine_distance);
+                if dist < next_dist {
+                    next_dist = dist;
+                    next = i;
+                }
+            }
+            playlist.push(pool.swap_remove(next));
+        }
+
+        // Write out as extended m3u
+        let mut m3u = String::from("#EXTM3U\n");
+        for stored in &playlist {
+            m3u.push_str(&format!(
+                "#EXTINF:-1,{} - {}\n{}\n",
+                stored.tags.artist,
+                stored.tags.title,
+                store.song_path(stored)?.display()
             ));
-            if path.exists() {
-                // Load song
-                let mut file = fs::File::open(&path).unwrap();
-
-                // Compare current file hash against stored
-                let mut hasher = blake3::Hasher::new();
-                io::copy(&mut file, &mut hasher).unwrap();
-                let fhash = hasher.finalize().as_bytes().to_owned();
-                if fhash != stored.fhash {
-                    println!(
-                        "{:x}_{:x} - {} \t| stored differs from file! Updating...",
-                        stored.phash >> 32,
-                        (stored.phash << 96) >> 96,
-                        stored.title
-                    );
+        }
+        if let Some(output) = sub_m.value_of("output") {
+            fs::write(output, m3u)?;
+        } else {
+            print!("{}", m3u);
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("art") {
+        let mut stored = find_by_id(&db, sub_m.value_of("id").unwrap())?.ok_or("id not in db!")?;
 
-                    // Load song into ffmpeg
-                    let format = ffmpeg::format::input(&path).unwrap();
-                    let mut title = String::default();
-                    let mut artist = String::default();
-                    let mut album = String::default();
-                    if let Some(t) = format.stream(0).unwrap().metadata().get("title") {
-                        title = t.to_owned();
-                    }
-                    if let Some(a) = format.stream(0).unwrap().metadata().get("artist") {
-                        artist = a.to_owned();
-                    }
-                    if let Some(a) = format.stream(0).unwrap().metadata().get("album") {
-                        album = a.to_owned();
+        if let Some(sub_m) = sub_m.subcommand_matches("extract") {
+            let art = stored.art.as_ref().ok_or("song has no art!")?;
+            fs::copy(art_path(msp, art), sub_m.value_of("output").unwrap())?;
+        } else if let Some(sub_m) = sub_m.subcommand_matches("set") {
+            let picture = Picture::from_file(Path::new(sub_m.value_of("image").unwrap()))?;
+            stored.art = Some(picture.store(msp)?);
+            retag(&store, &mut stored, Some(&picture))?;
+            insert_stored(&db, &stored)?;
+        } else if sub_m.subcommand_matches("remove").is_some() {
+            stored.art = None;
+            retag(&store, &mut stored, None)?;
+            insert_stored(&db, &stored)?;
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("export") {
+        let template = Template::parse(sub_m.value_of("template").unwrap())?;
+        let mode = Mode::from_name(sub_m.value_of("mode").unwrap()).unwrap();
+        // Exported files would look like orphans to sync
+        let dir = Path::new(sub_m.value_of("path").unwrap());
+        fs::create_dir_all(dir)?;
+        if fs::canonicalize(dir)?.starts_with(&store.path) {
+            return Err("can't export into the store!".into());
+        }
+
+        let songs = iter_stored(&db).collect::<Result<Vec<_>, _>>()?;
+        failed.extend(export::export(&store, dir, &songs, &template, &mode)?);
+    } else if let Some(sub_m) = matches.subcommand_matches("push") {
+        let mode = match sub_m.value_of("profile") {
+            Some(profile) => {
+                // Fail before anything is pushed
+                store.profiles.profile(profile)?;
+                Mode::Transcode(profile.to_owned())
+            }
+            None => Mode::Copy,
+        };
+        let template = Template::parse(sub_m.value_of("template").unwrap())?;
+        // An unmounted device leaves an empty mount point, which only has a manifest if
+        // something was pushed onto it
+        let dir = Path::new(sub_m.value_of("path").unwrap());
+        if !dir.is_dir() || !(sub_m.is_present("init") || Manifest::exists(dir)) {
+            return Err("device not mounted! (use --init to push to it the first time)".into());
+        }
+        if fs::canonicalize(dir)?.starts_with(&store.path) {
+            return Err("can't push into the store!".into());
+        }
+
+        // Narrow down songs using the indexes, then the playlist and query
+        let mut songs = match lookup_tags(&db, sub_m)? {
+            Some(ids) => ids
+                .into_iter()
+                .filter_map(|id| get_stored(&db, id).transpose())
+                .collect::<Result<Vec<_>, _>>()?,
+            None => iter_stored(&db).collect::<Result<Vec<_>, _>>()?,
+        };
+        if let Some(playlist) = sub_m.value_of("playlist") {
+            let ids = read_playlist(Path::new(playlist))?;
+            songs.retain(|stored| ids.contains(&stored.id));
+        }
+        if let Some(query) = sub_m.value_of("query") {
+            let query = Query::parse(query);
+            songs.retain(|stored| query.matches(stored));
+        }
+
+        println!("Pushing {} songs", songs.len());
+        failed.extend(export::export(&store, dir, &songs, &template, &mode)?);
+    } else if let Some(sub_m) = matches.subcommand_matches("layout") {
+        match sub_m.value_of("layout").and_then(Layout::from_name) {
+            Some(layout) => {
+                // The layout is only recorded once every song moved, run again to finish a move
+                let target = Store {
+                    path: store.path.clone(),
+                    layout,
+                    profiles: store.profiles.clone(),
+                };
+                for stored in iter_stored(&db) {
+                    let stored = stored?;
+                    let moved = store.song_path(&stored).and_then(|from| {
+                        if from.exists() {
+                            target.place(&from, &stored)?;
+                        }
+                        Ok(())
+                    });
+                    if let Err(e) = moved {
+                        println!("{} \t| failed to move! {}", fmt_id(stored.id), e);
+                        failed.push((fmt_id(stored.id), e));
                     }
+                }
+                if failed.is_empty() {
+                    layout.save(&db)?;
+                    println!("Store is now {}", layout.name());
+                }
+            }
+            None => println!("Store is {}", store.layout.name()),
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("index") {
+        if sub_m.subcommand_matches("rebuild").is_some() {
+            rebuild_indexes(&db)?;
+            println!("Rebuilt indexes!");
+        }
+    } else if let Some(sub_m) = matches.subcommand_matches("sync") {
+        let dry_run = sub_m.is_present("dry-run");
 
-                    // Insert into db
-                    db.insert(
-                        stored.phash.to_be_bytes(),
-                        bincode::serialize(&Stored {
-                            fhash,
-                            phash: stored.phash,
-
-                            title,
-                            artist,
-                            album,
-
-                            analysis: stored.analysis,
-                        })
-                        .unwrap(),
-                    )
-                    .unwrap();
+        // Plan changes before touching anything
+        let mut total = 0;
+        let mut changes = Vec::new();
+        for (k, v) in db.iter().filter_map(|f| f.ok()) {
+            total += 1;
+            match plan_song(&store, &v) {
+                Ok(Some(change)) => changes.push(change),
+                Ok(None) => {}
+                Err(e) => {
+                    println!("{} \t| failed to sync! {}", fmt_key(&k), e);
+                    failed.push((fmt_key(&k), e));
                 }
-            } else {
-                println!(
-                    "{:x}_{:x} - {} \t| does exist anymore! Removing...",
-                    stored.phash >> 32,
-                    (stored.phash << 96) >> 96,
-                    stored.title
-                );
-                db.remove(stored.phash.to_be_bytes()).unwrap();
             }
-        });
+        }
+
+        // A store that isn't mounted looks like every song was deleted
+        let removals = changes
+            .iter()
+            .filter(|c| matches!(c, SyncChange::Remove(_)))
+            .count();
+        let max_delete: f32 = sub_m.value_of_t("max-delete")?;
+        let refused = !sub_m.is_present("force")
+            && removals > 0
+            && removals as f32 * 100.0 / total as f32 > max_delete;
+        let apply = !dry_run && !refused;
+
+        for change in changes {
+            let (stored, message) = match (&change, apply) {
+                (SyncChange::Update(stored, _), true) => {
+                    (stored, "stored differs from file! Updating...")
+                }
+                (SyncChange::Update(stored, _), false) => {
+                    (stored, "stored differs from file! Would update")
+                }
+                (SyncChange::Reanalyze(stored, _), true) => {
+                    (stored, "audio differs from stored! Reanalyzing...")
+                }
+                (SyncChange::Reanalyze(stored, _), false) => {
+                    (stored, "audio differs from stored! Would reanalyze")
+                }
+                (SyncChange::Remove(stored), true) => (stored, "does exist anymore! Removing..."),
+                (SyncChange::Remove(stored), false) => (stored, "does exist anymore! Would remove"),
+            };
+            let key = fmt_id(stored.id);
+            println!("{} - {} \t| {}", key, stored.tags.title, message);
+            if !apply {
+                continue;
+            }
+
+            let result = match change {
+                // Edited tags can move a song in the readable layout
+                SyncChange::Update(old, new) | SyncChange::Reanalyze(old, new) => store
+                    .relocate(&old, &new)
+                    .and_then(|_| insert_stored(&db, &new)),
+                SyncChange::Remove(stored) => remove_stored(&db, &stored),
+            };
+            if let Err(e) = result {
+                println!("{} \t| failed to sync! {}", key, e);
+                failed.push((key, e));
+            }
+        }
+        if refused && !dry_run {
+            return Err(format!(
+                "refusing to remove {} of {} songs! Check the store path or use --force",
+                removals, total
+            )
+            .into());
+        }
+
+        // Handle files in the store without a record
+        let action = match sub_m.value_of("orphans").unwrap() {
+            "adopt" => Some('a'),
+            "quarantine" => Some('q'),
+            "ignore" => Some('i'),
+            _ => None,
+        };
+        for path in find_orphans(&db, &store)? {
+            if dry_run {
+                println!("{} \t| not in db!", path.display());
+                continue;
+            }
+            if let Err(e) = sync_orphan(&db, &store, &path, action) {
+                println!("{} \t| failed to sync! {}", path.display(), e);
+                failed.push((path.display().to_string(), e));
+            }
+        }
+    } else if matches.subcommand_matches("migrate").is_some() {
+        let mut migrated = 0;
+        for (k, v) in db.iter().filter_map(|f| f.ok()) {
+            let version = schema::version(&v);
+            if version == schema::VERSION {
+                continue;
+            }
+            // Every older record is from before songs had ids
+            let result = decode(&v).and_then(|stored| assign_id(&db, &store, &k, stored));
+            match result {
+                Ok(()) => migrated += 1,
+                Err(e) => {
+                    println!("{} \t| failed to migrate! {}", fmt_key(&k), e);
+                    failed.push((fmt_key(&k), e));
+                }
+            }
+        }
+        if migrated > 0 {
+            rebuild_indexes(&db)?;
+        }
+        if failed.is_empty() {
+            meta.insert("ids", b"")?;
+        }
+        println!("Migrated {} songs to version {}", migrated, schema::VERSION);
     } else {
         std::process::exit(1);
     }
 
     db.flush()?;
 
+    // Report failures
+    if !failed.is_empty() {
+        println!("\n--- {} Failed ---", failed.len());
+        for (name, e) in &failed {
+            println!("{} \t| {}", name, e);
+        }
+        process::exit(1);
+    }
+
     Ok(())
 }
 
-// Finds the closest matching song from the database
-fn find_closest_song(db: &Db, title: &str, analysis: &Analysis) -> (u128, f32, u128, f32) {
-    let mut closest: u128 = 0;
-    let mut closest_dist = f32::MAX;
+// Ingests a single analyzed song into the store
+fn ingest_song(
+    db: &Db,
+    store: &Store,
+    path: &Path,
+    analyzed: Result<Analyzed, WusicError>,
+    options: &IngestOptions,
+    report: &mut Option<fs::File>,
+) -> Result<Outcome, WusicError> {
+    let policy = &options.policy;
+
+    // Load song
+    let Analyzed {
+        mut song,
+        fingerprint,
+        measurement,
+    } = analyzed?;
+    let format = ffmpeg::format::input(&path)?;
+
+    // Get current metadata
+    let mut tags = Tags::read(&format);
+    let duration = tags::duration(&format);
+
+    // perceptually hash song
+    let mut phash = gen_phash(&song.analysis);
+    // Unrelated songs can share a phash, only a matching fingerprint makes it a dupe
+    if let Some(stored) = find_same_song(db, store, phash, &fingerprint)? {
+        println!("HASH COLLISION!!! Same fingerprint so its an dupe! Skipping...");
+        println!("--- Prev Song ---");
+        println!(
+            "{} - {}\t| {}",
+            stored.tags.artist, stored.tags.title, stored.tags.album
+        );
+        println!("--- Curr Song ---");
+        println!("{} - {}\t| {}", tags.artist, tags.title, tags.album);
+        println!("-----------------\n");
+
+        write_report(
+            report,
+            &Decision {
+                path: &path.to_string_lossy(),
+                id: None,
+                phash: format!("{:x}_{:x}", phash >> 32, (phash << 96) >> 96),
+
+                title: &tags.title,
+                artist: &tags.artist,
+                album: &tags.album,
+
+                closest: fmt_id(stored.id),
+                closest_dist: 0.0,
+                tclosest: String::default(),
+                tclosest_dist: 0.0,
+
+                action: "collision",
+            },
+        )?;
+        return Ok(Outcome::Skipped);
+    }
+
+    // Start ingesting
+    println!("\n--- Curr Song ---");
+    println!(
+        "{} - {}\t| {}: {:x}_{:x}",
+        tags.artist,
+        tags.title,
+        tags.album,
+        phash >> 32,
+        (phash << 96) >> 96
+    );
+    // get closest song
+    let (closest, closest_dist, tclosest, tclosest_dist) =
+        find_closest_song(db, &tags.title, &song.analysis)?;
+    if let Some(tclosest_stored) = get_stored(db, tclosest)? {
+        println!("--- Closest Title (Dist: {}) ---", tclosest_dist);
+        println!(
+            "{} - {}\t| {}: {}",
+            tclosest_stored.tags.artist,
+            tclosest_stored.tags.title,
+            tclosest_stored.tags.album,
+            fmt_id(tclosest)
+        );
+    }
+    if let Some(closest_stored) = get_stored(db, closest)? {
+        println!("--- Closest Song (Dist: {}) ---", closest_dist);
+        println!(
+            "{} - {}\t| {}: {}",
+            closest_stored.tags.artist,
+            closest_stored.tags.title,
+            closest_stored.tags.album,
+            fmt_id(closest)
+        );
+    }
+    println!("-----------------");
+
+    // Records what was done with this song
+    let mut decide = |id: Option<u128>, phash: u128, tags: &Tags, action: &str| {
+        write_report(
+            report,
+            &Decision {
+                path: &path.to_string_lossy(),
+                id: id.map(fmt_id),
+                phash: format!("{:x}_{:x}", phash >> 32, (phash << 96) >> 96),
+
+                title: &tags.title,
+                artist: &tags.artist,
+                album: &tags.album,
+
+                closest: fmt_id(closest),
+                closest_dist,
+                tclosest: fmt_id(tclosest),
+                tclosest_dist,
+
+                action,
+            },
+        )
+    };
+
+    // The closest song having the same fingerprint means this one is a duplicate,
+    // which is only worth ingesting to replace it
+    let duplicate = match get_stored(db, closest)? {
+        Some(stored) => fingerprint::matches(&stored_fingerprint(store, &stored)?, &fingerprint),
+        None => false,
+    };
+    if duplicate {
+        println!("Fingerprint Already in DB!");
+    }
+
+    let key = if let Some(policy) = policy {
+        match policy.action {
+            'n' => {
+                // skip duplicates
+                if duplicate {
+                    println!("Duplicate so Skipping!");
+                    decide(None, phash, &tags, "duplicate")?;
+                    return Ok(Outcome::Skipped);
+                }
+                // skip if closer than the threshold
+                if closest != 0 && closest_dist < policy.skip_threshold {
+                    println!("Closer than {} so Skipping!", policy.skip_threshold);
+                    's'
+                } else {
+                    'n'
+                }
+            }
+            // Only replace songs this one is a near duplicate of
+            'r' => {
+                if duplicate || (closest != 0 && closest_dist < policy.replace_threshold) {
+                    'r'
+                } else {
+                    println!("Nothing close enough to replace, adding as new!");
+                    'n'
+                }
+            }
+            't' => {
+                let near = match get_stored(db, tclosest)? {
+                    Some(stored) if tclosest_dist >= policy.title_threshold => {
+                        stored
+                            .analysis
+                            .custom_distance(&song.analysis, cosine_distance)
+                            < policy.replace_threshold
+                            || fingerprint::matches(
+                                &stored_fingerprint(store, &stored)?,
+                                &fingerprint,
+                            )
+                    }
+                    _ => false,
+                };
+                if near {
+                    't'
+                } else {
+                    println!("No title close enough to replace, adding as new!");
+                    'n'
+                }
+            }
+            action => action,
+        }
+    } else {
+        requestty::prompt_one(
+            requestty::Question::expand("process")
+                .message("Choose process")
+                .choices(vec![
+                    ('n', "New"),
+                    ('r', "Replace closest"),
+                    ('t', "Replace closest title"),
+                    ('s', "Skip"),
+                    ('x', "Abort"),
+                ])
+                .default(if duplicate { 's' } else { 'n' })
+                .build(),
+        )
+        .unwrap()
+        .as_expand_item()
+        .unwrap()
+        .key
+    };
+    if key == 'n' || key == 'r' || key == 't' {
+        // Get song that is being replaced
+        let replaced = match key {
+            'r' => get_stored(db, closest)?,
+            't' => get_stored(db, tclosest)?,
+            _ => None,
+        };
+        if key != 'n' && replaced.is_none() {
+            println!("Nothing to replace! Skipping...");
+            decide(None, phash, &tags, "skip")?;
+            return Ok(Outcome::Skipped);
+        }
+
+        // Carry over old metadata as defaults
+        if let Some(replaced) = &replaced {
+            if policy.is_some() {
+                // only fill in missing tags
+                tags.fill_from(&replaced.tags);
+            } else if requestty::prompt_one(
+                requestty::Question::confirm("carry")
+                    .message("Use metadata of replaced song as defaults?")
+                    .default(true)
+                    .build(),
+            )
+            .unwrap()
+            .as_bool()
+            .unwrap()
+            {
+                tags = replaced.tags.clone();
+            }
+        }
+
+        // Get new metadata
+        if policy.is_none() {
+            tags = tags.prompt();
+        }
+
+        // New songs get a new id, even when replacing, so the replaced file is never overwritten
+        let id = new_id();
+        let profile = &options.profile;
+
+        // Find art, keeping the replaced song's art if there is none
+        let mut picture = Picture::find(path)?;
+        if picture.is_none() {
+            if let Some(art) = replaced.as_ref().and_then(|r| r.art.as_ref()) {
+                picture = Some(Picture::load(&store.path, art)?);
+            }
+        }
+        let art = picture.as_ref().map(|p| p.store(&store.path)).transpose()?;
+
+        // Gain tags are written along with the others
+        let loudness = measurement
+            .gain()
+            .map(|track| Loudness { track, album: None });
+        let group = AlbumGroup::of(&tags, path);
+
+        let embedded = picture.as_ref().filter(|_| profile.vorbis_comments());
+        let gain = match &loudness {
+            Some(loudness) => loudness.tags(profile),
+            None => Vec::new(),
+        };
+        let metadata = tags.metadata(profile, embedded, &gain);
+
+        // Journal the ingest, so a crash can be rolled back
+        let tmp_path = store.path.join(format!("{}.tmp", fmt_id(id)));
+        let mut entry = journal::Entry {
+            tmp: tmp_path.clone(),
+            target: None,
+            replaced: match &replaced {
+                Some(r) => Some((r.id, store.song_path(r)?)),
+                None => None,
+            },
+        };
+        journal::write(db, id, &entry)?;
+
+        if options.copy {
+            // Copy over file
+            remux(path, &tmp_path, &profile.format, metadata)?;
+        } else {
+            // Transcode over file
+            transcode(path, &tmp_path, profile, metadata)?;
+            // Recalculate perceptual hash
+            song = Song::new(&tmp_path)?;
+            phash = gen_phash(&song.analysis);
+            println!("New phash: {:x}_{:x}", phash >> 32, (phash << 96) >> 96);
+        }
+
+        // Hash file
+        let fhash = hash_file(&tmp_path)?;
+        let ahash = hash_audio(&tmp_path)?;
+        let stored = Stored {
+            id,
+            fhash,
+            ahash: Some(ahash),
+            phash,
+            fingerprint: Some(fingerprint),
+
+            tags,
+            duration,
+            art,
+            profile: options.profile_name.clone(),
+            loudness,
+
+            analysis: song.analysis,
+        };
+
+        // Move tmp file over to correct position
+        entry.target = Some((id, store.song_path(&stored)?));
+        journal::write(db, id, &entry)?;
+        store.place(&tmp_path, &stored)?;
+
+        decide(
+            Some(id),
+            phash,
+            &stored.tags,
+            match key {
+                'r' => "replace",
+                't' => "replace-title",
+                _ => "new",
+            },
+        )?;
+
+        // Insert into db, on disk before the replaced song goes as this is what
+        // recovery takes as the ingest having happened
+        insert_stored(db, &stored)?;
+        db.flush()?;
+
+        // Remove replaced song, now that the new one is in
+        if let Some(replaced) = replaced {
+            let old_path = store.song_path(&replaced)?;
+            println!(
+                "Replacing {} - {}",
+                fmt_id(replaced.id),
+                replaced.tags.title
+            );
+            remove_stored(db, &replaced)?;
+            if old_path.exists() {
+                fs::remove_file(&old_path)?;
+                store.remove_empty_dirs(&old_path);
+            }
+        }
+        journal::end(db, id)?;
+
+        return Ok(Outcome::Ingested(Ingested {
+            id,
+            group,
+            measurement,
+        }));
+    } else if key == 's' {
+        decide(None, phash, &tags, "skip")?;
+    } else if key == 'x' {
+        decide(None, phash, &tags, "abort")?;
+        return Ok(Outcome::Aborted);
+    } else {
+        panic!("process not defined!");
+    }
+
+    Ok(Outcome::Skipped)
+}
+
+// Works out how a stored song has to change to match its file in the store
+fn plan_song(store: &Store, v: &[u8]) -> Result<Option<SyncChange>, WusicError> {
+    let stored = decode(v)?;
+
+    let path = store.song_path(&stored)?;
+    if !path.exists() {
+        return Ok(Some(SyncChange::Remove(stored)));
+    }
+
+    // Compare current file hash against stored
+    let fhash = hash_file(&path)?;
+    if fhash == stored.fhash {
+        return Ok(None);
+    }
+
+    // Load song into ffmpeg
+    let format = ffmpeg::format::input(&path)?;
+    let ahash = hash_audio(&path)?;
 
-    let mut tclosest: u128 = 0;
-    let mut tclosest_dist = 0.0;
+    // Only the tags changed
+    if stored.ahash == Some(ahash) {
+        let updated = Stored {
+            id: stored.id,
+            fhash,
+            ahash: Some(ahash),
+            phash: stored.phash,
+            fingerprint: stored.fingerprint.clone(),
+
+            tags: Tags::read(&format),
+            duration: tags::duration(&format),
+            art: stored.art.clone(),
+            profile: stored.profile.clone(),
+            loudness: stored.loudness.clone(),
+
+            analysis: stored.analysis.clone(),
+        };
+        return Ok(Some(SyncChange::Update(stored, updated)));
+    }
+
+    // The audio changed, or was never hashed, so analyze it again
+    let song = Song::new(&path)?;
+    let updated = Stored {
+        id: stored.id,
+        fhash,
+        ahash: Some(ahash),
+        phash: gen_phash(&song.analysis),
+        fingerprint: Some(fingerprint::compute(&path)?),
+
+        tags: Tags::read(&format),
+        duration: tags::duration(&format),
+        art: stored.art.clone(),
+        profile: stored.profile.clone(),
+        loudness: loudness::measure(&path)?
+            .gain()
+            .map(|track| Loudness { track, album: None }),
+
+        analysis: song.analysis,
+    };
+    Ok(Some(SyncChange::Reanalyze(stored, updated)))
+}
 
-    db.iter().filter_map(|f| f.ok()).for_each(|(_, v)| {
-        let stored: Stored = bincode::deserialize(&v).unwrap();
+// Finds song files in the store that no record points to
+fn find_orphans(db: &Db, store: &Store) -> Result<Vec<PathBuf>, WusicError> {
+    let extensions: Vec<&str> = store
+        .profiles
+        .profiles
+        .values()
+        .map(|p| p.extension.as_str())
+        .chain(["opus"])
+        .collect();
 
-        // Perceptually closer
-        let dist = stored.analysis.custom_distance(analysis, cosine_distance);
-        if dist < closest_dist {
-            closest_dist = dist;
-            closest = stored.phash;
+    // Where the layout puts every stored song
+    let mut known = HashSet::new();
+    for stored in iter_stored(db) {
+        known.insert(store.song_path(&stored?)?);
+    }
+
+    let mut orphans = Vec::new();
+    for entry in WalkDir::new(&store.path)
+        .into_iter()
+        .filter_entry(|e| store.holds_songs(e.path()))
+        .filter_map(|e| e.ok())
+    {
+        let path = entry.into_path();
+        let extension = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if !path.is_file() || !extensions.contains(&extension.as_str()) {
+            continue;
         }
 
-        // Title closer
-        let tdist = fuzzy_compare(title, &stored.title);
-        if tdist > tclosest_dist {
-            tclosest_dist = tdist;
-            tclosest = stored.phash;
+        if !known.contains(&path) {
+            orphans.push(path);
         }
+    }
+    orphans.sort();
+
+    Ok(orphans)
+}
+
+// Adopts, quarantines or ignores a song file without a record
+fn sync_orphan(
+    db: &Db,
+    store: &Store,
+    path: &Path,
+    action: Option<char>,
+) -> Result<(), WusicError> {
+    println!("{} \t| not in db!", path.display());
+    let key = action.unwrap_or_else(|| {
+        requestty::prompt_one(
+            requestty::Question::expand("orphan")
+                .message("Choose process")
+                .choices(vec![
+                    ('a', "Adopt into db"),
+                    ('q', "Quarantine"),
+                    ('i', "Ignore"),
+                ])
+                .default('a')
+                .build(),
+        )
+        .unwrap()
+        .as_expand_item()
+        .unwrap()
+        .key
     });
 
-    (closest, closest_dist, tclosest, tclosest_dist)
+    match key {
+        'a' => adopt(db, store, path),
+        'q' => quarantine(store, path),
+        _ => Ok(()),
+    }
+}
+
+// Analyzes a song file in the store and adds it to the db
+fn adopt(db: &Db, store: &Store, path: &Path) -> Result<(), WusicError> {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let profile = match store.profiles.by_extension(&extension) {
+        Some(profile) => profile.to_owned(),
+        None => {
+            println!("{} \t| no profile makes such files!", path.display());
+            return quarantine(store, path);
+        }
+    };
+
+    // perceptually hash and fingerprint song
+    let song = Song::new(path)?;
+    let fingerprint = fingerprint::compute(path)?;
+    let phash = gen_phash(&song.analysis);
+    if let Some(stored) = find_same_song(db, store, phash, &fingerprint)? {
+        println!(
+            "{} \t| already in db as {} - {}!",
+            path.display(),
+            fmt_id(stored.id),
+            stored.tags.title
+        );
+        return quarantine(store, path);
+    }
+
+    // Keep the id in the file name if no other song has it
+    let id = match store::path_id(path) {
+        Some(id) if get_stored(db, id)?.is_none() => id,
+        _ => new_id(),
+    };
+
+    // Move into place
+    let format = ffmpeg::format::input(&path)?;
+    let mut stored = Stored {
+        id,
+        fhash: [0; 32],
+        ahash: Some(hash_audio(path)?),
+        phash,
+        fingerprint: Some(fingerprint),
+
+        tags: Tags::read(&format),
+        duration: tags::duration(&format),
+        art: Picture::find(path)?
+            .map(|p| p.store(&store.path))
+            .transpose()?,
+        profile,
+        loudness: loudness::measure(path)?
+            .gain()
+            .map(|track| Loudness { track, album: None }),
+
+        analysis: song.analysis,
+    };
+    let new_path = store.place(path, &stored)?;
+    stored.fhash = hash_file(&new_path)?;
+
+    println!("{} - {} \t| adopted", fmt_id(id), stored.tags.title);
+    insert_stored(db, &stored)
+}
+
+// Moves a song file out of the way, into the store's quarantine directory
+fn quarantine(store: &Store, path: &Path) -> Result<(), WusicError> {
+    // Keep where the file was in the store, so songs with the same name don't collide
+    let relative = match path.strip_prefix(&store.path) {
+        Ok(relative) => relative,
+        Err(_) => Path::new(path.file_name().unwrap_or_default()),
+    };
+    let mut target = store.path.join("quarantine").join(relative);
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // Never overwrite something quarantined earlier
+    let stem = target.file_stem().unwrap_or_default().to_owned();
+    let extension = target.extension().map(|e| e.to_owned());
+    let mut n = 1;
+    while target.symlink_metadata().is_ok() {
+        let mut name = stem.clone();
+        name.push(format!(" ({})", n));
+        if let Some(extension) = &extension {
+            name.push(".");
+            name.push(extension);
+        }
+        target.set_file_name(name);
+        n += 1;
+    }
+
+    fs::rename(path, &target)?;
+    store.remove_empty_dirs(path);
+    println!("{} \t| quarantined", path.display());
+    Ok(())
+}
+
+// Whether a file has audio to ingest, files ffmpeg can't open are tried anyway so
+// they are reported as failures
+fn has_audio(path: &Path) -> bool {
+    match ffmpeg::format::input(&path) {
+        Ok(input) => input.streams().best(ffmpeg::media::Type::Audio).is_some(),
+        Err(_) => true,
+    }
+}
+
+// Rewrites the tags and art of a stored song without transcoding it
+fn retag(store: &Store, stored: &mut Stored, picture: Option<&Picture>) -> Result<(), WusicError> {
+    let path = store.song_path(stored)?;
+    let tmp_path = store.path.join(format!("{}.tmp", fmt_id(stored.id)));
+    let profile = store.profiles.profile(&stored.profile)?;
+
+    // Keep loudness tags around, from the file if they were never measured
+    let gain = match &stored.loudness {
+        Some(loudness) => loudness.tags(profile),
+        None => tags::gain(&ffmpeg::format::input(&path)?),
+    };
+    let picture = picture.filter(|_| profile.vorbis_comments());
+    if let Err(e) = remux(
+        &path,
+        &tmp_path,
+        &profile.format,
+        stored.tags.metadata(profile, picture, &gain),
+    ) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    fs::rename(&tmp_path, &path)?;
+
+    stored.fhash = hash_file(&path)?;
+    Ok(())
+}
+
+// Computes the gain of an album and writes it to its songs, measuring songs that weren't
+fn album_gain(
+    db: &Db,
+    store: &Store,
+    group: &AlbumGroup,
+    mut measured: HashMap<u128, Measurement>,
+) -> Result<(), WusicError> {
+    let mut ids: Vec<u128> = measured.keys().copied().collect();
+    // Songs of the album that are already stored count too
+    if let AlbumGroup::Tagged(artist, album) = group {
+        for id in lookup(db, "album", album)? {
+            if ids.contains(&id) {
+                continue;
+            }
+            if let Some(stored) = get_stored(db, id)? {
+                if stored.tags.album_key() == Some((artist.clone(), album.clone())) {
+                    ids.push(id);
+                }
+            }
+        }
+    }
+
+    let mut songs = Vec::new();
+    let mut measurements = Vec::new();
+    for id in ids {
+        if let Some(stored) = get_stored(db, id)? {
+            measurements.push(match measured.remove(&id) {
+                Some(measurement) => measurement,
+                None => loudness::measure(&store.song_path(&stored)?)?,
+            });
+            songs.push(stored);
+        }
+    }
+
+    let album = Measurement::album(&measurements);
+    match &album {
+        Some(album) => println!("{} \t| album loudness {:.2} LUFS", group, album.loudness),
+        None => println!("{} \t| album is silent", group),
+    }
+    for (mut stored, measurement) in songs.into_iter().zip(measurements) {
+        // Silent songs get no gain, rather than one that would blow up their noise floor
+        stored.loudness = measurement.gain().map(|track| Loudness { track, album });
+        let picture = stored
+            .art
+            .as_ref()
+            .map(|art| Picture::load(&store.path, art))
+            .transpose()?;
+        retag(store, &mut stored, picture.as_ref())?;
+        insert_stored(db, &stored)?;
+    }
+
+    Ok(())
+}
+
+// Fingerprint of a stored song, computed from its file if it was stored without one
+fn stored_fingerprint(store: &Store, stored: &Stored) -> Result<Vec<u32>, WusicError> {
+    match &stored.fingerprint {
+        Some(fingerprint) => Ok(fingerprint.clone()),
+        None => fingerprint::compute(&store.song_path(stored)?),
+    }
+}
+
+// Ids of the songs with every tag given as an argument, if any were
+fn lookup_tags(db: &Db, sub_m: &ArgMatches) -> Result<Option<Vec<u128>>, WusicError> {
+    let mut ids: Option<Vec<u128>> = None;
+    for index in INDEXES {
+        if let Some(value) = sub_m.value_of(index) {
+            let found = lookup(db, index, value)?;
+            ids = Some(match ids {
+                Some(ids) => ids.into_iter().filter(|id| found.contains(id)).collect(),
+                None => found,
+            });
+        }
+    }
+    Ok(ids)
+}
+
+// Ids of the stored songs in an m3u playlist, like the ones playlist writes
+fn read_playlist(path: &Path) -> Result<HashSet<u128>, WusicError> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| store::path_id(Path::new(line)))
+        .collect())
+}
+
+// Finds a stored song with the same phash and fingerprint
+fn find_same_song(
+    db: &Db,
+    store: &Store,
+    phash: u128,
+    fingerprint: &[u32],
+) -> Result<Option<Stored>, WusicError> {
+    for id in lookup_phash(db, phash)? {
+        if let Some(stored) = get_stored(db, id)? {
+            if fingerprint::matches(&stored_fingerprint(store, &stored)?, fingerprint) {
+                return Ok(Some(stored));
+            }
+        }
+    }
+    Ok(None)
+}
+
+// Finds a stored song by its id, or by its phash like songs were found before they had ids
+fn find_by_id(db: &Db, s: &str) -> Result<Option<Stored>, WusicError> {
+    if let Some(id) = parse_id(s) {
+        return get_stored(db, id);
+    }
+    match parse_phash(s)
+        .map(|phash| lookup_phash(db, phash))
+        .transpose()?
+    {
+        Some(ids) if !ids.is_empty() => get_stored(db, ids[0]),
+        _ => Ok(None),
+    }
+}
+
+// Gives a song stored under its phash an id, moving its file to the new name
+fn assign_id(db: &Db, store: &Store, key: &[u8], mut stored: Stored) -> Result<(), WusicError> {
+    let old_key = u128::from_be_bytes(key.try_into().unwrap_or_default());
+    let old_name = format!("{:x}_{:x}", old_key >> 32, (old_key << 96) >> 96);
+    let old_path = store.path.join(format!(
+        "{}.{}",
+        old_name,
+        store.profiles.extension(&stored.profile)?
+    ));
+
+    // A rerun finds the file already moved to where the same id puts it
+    stored.id = legacy_id(old_key);
+    if old_path.exists() {
+        store.place(&old_path, &stored)?;
+    } else if !store.song_path(&stored)?.exists() {
+        println!("{} \t| file is missing!", old_name);
+    }
+    db.insert(stored.id.to_be_bytes(), encode(&stored)?)?;
+    db.remove(key)?;
+    db.flush()?;
+
+    println!(
+        "{} \t| now {} - {}",
+        old_name,
+        fmt_id(stored.id),
+        stored.tags.title
+    );
+    Ok(())
+}
+
+// Hashes the audio packets of a song with blake3, leaving out tags and art
+fn hash_audio(path: &Path) -> Result<[u8; 32], WusicError> {
+    let mut format = ffmpeg::format::input(&path)?;
+    let index = format
+        .streams()
+        .best(ffmpeg::media::Type::Audio)
+        .ok_or(ffmpeg::Error::StreamNotFound)?
+        .index();
+
+    let mut hasher = blake3::Hasher::new();
+    for (stream, packet) in format.packets() {
+        if stream.index() == index {
+            if let Some(data) = packet.data() {
+                hasher.update(data);
+            }
+        }
+    }
+    Ok(hasher.finalize().as_bytes().to_owned())
+}
+
+// Hashes a file with blake3
+fn hash_file(path: &Path) -> Result<[u8; 32], WusicError> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().as_bytes().to_owned())
+}
+
+// Appends an ingest decision to the report, if there is one
+fn write_report(report: &mut Option<fs::File>, decision: &Decision) -> Result<(), WusicError> {
+    if let Some(file) = report {
+        writeln!(file, "{}", serde_json::to_string(decision)?)?;
+    }
+    Ok(())
+}
+
+// Parses a phash formatted as {:x}_{:x}
+fn parse_phash(s: &str) -> Option<u128> {
+    let (high, low) = s.split_once('_')?;
+    let high = u128::from_str_radix(high, 16).ok()?;
+    let low = u128::from_str_radix(low, 16).ok()?;
+    Some((high << 32) | low)
 }
 
 // Generate a sorta perceptual hash from a song analysis
@@ -0,0 +1,133 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::error::WusicError;
+
+// Profile used for songs from before profiles existed
+pub const LEGACY_PROFILE: &str = "opus160";
+
+// How songs are encoded into the store
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Profile {
+    // ffmpeg encoder
+    pub codec: String,
+    pub bitrate: Option<String>,
+    pub sample_rate: Option<u32>,
+    // ffmpeg muxer
+    pub format: String,
+    pub extension: String,
+}
+
+impl Profile {
+    fn new(codec: &str, bitrate: Option<&str>, format: &str, extension: &str) -> Profile {
+        Profile {
+            codec: codec.to_owned(),
+            bitrate: bitrate.map(|b| b.to_owned()),
+            sample_rate: if codec == "libopus" {
+                Some(48000)
+            } else {
+                None
+            },
+            format: format.to_owned(),
+            extension: extension.to_owned(),
+        }
+    }
+
+    // Bitrate in bits per second, from values like "160k"
+    pub fn bit_rate(&self) -> Option<usize> {
+        let bitrate = self.bitrate.as_ref()?.trim();
+        match bitrate.strip_suffix(|c| c == 'k' || c == 'K') {
+            Some(kbits) => kbits.parse::<usize>().ok().map(|k| k * 1000),
+            None => bitrate.parse().ok(),
+        }
+    }
+
+    // Whether art can be embedded as a METADATA_BLOCK_PICTURE comment
+    pub fn vorbis_comments(&self) -> bool {
+        matches!(self.format.as_str(), "opus" | "ogg")
+    }
+
+    // Whether songs are muxed as mp4, which only keeps tags iTunes has atoms for
+    pub fn mp4(&self) -> bool {
+        matches!(self.format.as_str(), "ipod" | "mp4" | "mov")
+    }
+}
+
+// Encoding profiles, read from a json config file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Profiles {
+    pub default: String,
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+impl Default for Profiles {
+    fn default() -> Self {
+        let mut profiles = BTreeMap::new();
+        profiles.insert(
+            "opus96".to_owned(),
+            Profile::new("libopus", Some("96k"), "opus", "opus"),
+        );
+        profiles.insert(
+            "opus128".to_owned(),
+            Profile::new("libopus", Some("128k"), "opus", "opus"),
+        );
+        profiles.insert(
+            "opus160".to_owned(),
+            Profile::new("libopus", Some("160k"), "opus", "opus"),
+        );
+        profiles.insert(
+            "flac".to_owned(),
+            Profile::new("flac", None, "flac", "flac"),
+        );
+        profiles.insert(
+            "aac256".to_owned(),
+            Profile::new("aac", Some("256k"), "ipod", "m4a"),
+        );
+
+        Profiles {
+            default: LEGACY_PROFILE.to_owned(),
+            profiles,
+        }
+    }
+}
+
+impl Profiles {
+    // Loads profiles from a config file, falling back to the built in ones
+    pub fn load(path: &Path) -> Result<Profiles, WusicError> {
+        if path.exists() {
+            Ok(serde_json::from_slice(&fs::read(path)?)?)
+        } else {
+            Ok(Profiles::default())
+        }
+    }
+
+    // Gets a profile by name
+    pub fn get(&self, name: &str) -> Option<&Profile> {
+        self.profiles.get(name)
+    }
+
+    // Gets a profile by name, for songs and commands that need it to exist
+    pub fn profile(&self, name: &str) -> Result<&Profile, WusicError> {
+        self.get(name)
+            .ok_or_else(|| WusicError::UnknownProfile(name.to_owned()))
+    }
+
+    // Name of a profile that makes files with an extension, preferring the default
+    pub fn by_extension(&self, extension: &str) -> Option<&str> {
+        if self.get(&self.default).map(|p| p.extension.as_str()) == Some(extension) {
+            return Some(&self.default);
+        }
+        self.profiles
+            .iter()
+            .find(|(_, p)| p.extension == extension)
+            .map(|(name, _)| name.as_str())
+    }
+
+    // File extension of songs made with a profile
+    pub fn extension(&self, name: &str) -> Result<&str, WusicError> {
+        Ok(&self.profile(name)?.extension)
+    }
+}
@@ -0,0 +1,63 @@
+use crate::db::{normalize, Stored};
+
+// Tags a query term can be limited to
+const FIELDS: [&str; 7] = [
+    "title",
+    "artist",
+    "album",
+    "albumartist",
+    "genre",
+    "composer",
+    "date",
+];
+
+// A search over tags, like "pink floyd album:the wall", where every term has to match.
+// Words without a field match the title, artist or album.
+pub struct Query {
+    terms: Vec<(Option<String>, String)>,
+}
+
+impl Query {
+    pub fn parse(query: &str) -> Query {
+        let mut terms: Vec<(Option<String>, String)> = Vec::new();
+        for word in query.split_whitespace() {
+            match word.split_once(':') {
+                Some((field, value)) if FIELDS.contains(&field.to_lowercase().as_str()) => {
+                    terms.push((Some(field.to_lowercase()), value.to_owned()))
+                }
+                // Following words are part of the same term
+                _ => match terms.last_mut() {
+                    Some((_, value)) => {
+                        value.push(' ');
+                        value.push_str(word);
+                    }
+                    None => terms.push((None, word.to_owned())),
+                },
+            }
+        }
+
+        Query {
+            terms: terms
+                .into_iter()
+                .map(|(field, value)| (field, normalize(&value)))
+                .collect(),
+        }
+    }
+
+    pub fn matches(&self, stored: &Stored) -> bool {
+        let tags = &stored.tags;
+        self.terms.iter().all(|(field, value)| {
+            let values = match field.as_deref() {
+                Some("title") => vec![&tags.title],
+                Some("artist") => vec![&tags.artist],
+                Some("album") => vec![&tags.album],
+                Some("albumartist") => vec![&tags.album_artist],
+                Some("genre") => vec![&tags.genre],
+                Some("composer") => vec![&tags.composer],
+                Some("date") => vec![&tags.date],
+                _ => vec![&tags.title, &tags.artist, &tags.album],
+            };
+            values.iter().any(|v| normalize(v).contains(value.as_str()))
+        })
+    }
+}
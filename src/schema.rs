@@ -0,0 +1,219 @@
+use bliss_audio::Analysis;
+use serde::{Deserialize, Serialize};
+
+use crate::error::WusicError;
+use crate::profile::LEGACY_PROFILE;
+
+// Records are stored as `MAGIC version bincode(Stored)`. Records written before
+// versioning have no header and are treated as version 0.
+const MAGIC: &[u8; 4] = b"WUS\0";
+const HEADER: usize = MAGIC.len() + 2;
+pub const VERSION: u16 = 2;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Stored {
+    // ULID given to the song when it was first stored, its key and file name
+    pub id: u128,
+    pub fhash: [u8; 32],
+    // Hash of just the audio packets, so tag edits can be told apart from new audio
+    pub ahash: Option<[u8; 32]>,
+    pub phash: u128,
+    pub fingerprint: Option<Vec<u32>>,
+
+    pub tags: Tags,
+    pub duration: Option<f64>,
+    pub art: Option<String>,
+    pub profile: String,
+    pub loudness: Option<Loudness>,
+
+    pub analysis: Analysis,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Tags {
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub album_artist: String,
+    pub track: Option<u32>,
+    pub disc: Option<u32>,
+    pub date: String,
+    pub genre: String,
+    pub composer: String,
+}
+
+// Integrated loudness in LUFS and linear true peak
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Gain {
+    pub loudness: f64,
+    pub peak: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Loudness {
+    pub track: Gain,
+    pub album: Option<Gain>,
+}
+
+// Version 0 and 1, from before songs had ids and were keyed by their phash
+#[derive(Deserialize)]
+struct StoredV1 {
+    fhash: [u8; 32],
+    phash: u128,
+
+    title: String,
+    artist: String,
+    album: String,
+
+    analysis: Analysis,
+}
+
+// Migrate gives these songs real ids, until then they keep their phash as one
+impl From<StoredV1> for Stored {
+    fn from(old: StoredV1) -> Self {
+        Stored {
+            id: old.phash,
+            fhash: old.fhash,
+            ahash: None,
+            phash: old.phash,
+            fingerprint: None,
+
+            tags: Tags {
+                title: old.title,
+                artist: old.artist,
+                album: old.album,
+                ..Tags::default()
+            },
+            duration: None,
+            art: None,
+            profile: LEGACY_PROFILE.to_owned(),
+            loudness: None,
+
+            analysis: old.analysis,
+        }
+    }
+}
+
+// Gets the version of an encoded record
+pub fn version(bytes: &[u8]) -> u16 {
+    match bytes.strip_prefix(MAGIC) {
+        Some(rest) if rest.len() >= 2 => u16::from_be_bytes([rest[0], rest[1]]),
+        _ => 0,
+    }
+}
+
+// Encodes a record at the current version
+pub fn encode(stored: &Stored) -> Result<Vec<u8>, WusicError> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VERSION.to_be_bytes());
+    bincode::serialize_into(&mut bytes, stored)?;
+    Ok(bytes)
+}
+
+// Decodes a record, upgrading it from older versions
+pub fn decode(bytes: &[u8]) -> Result<Stored, WusicError> {
+    match version(bytes) {
+        // Version 0 has the same layout as version 1, just no header
+        0 => Ok(bincode::deserialize::<StoredV1>(bytes)?.into()),
+        1 => Ok(bincode::deserialize::<StoredV1>(&bytes[HEADER..])?.into()),
+        2 => Ok(bincode::deserialize(&bytes[HEADER..])?),
+        v => Err(WusicError::UnknownVersion(v)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bliss_audio::NUMBER_FEATURES;
+
+    fn features() -> [f32; NUMBER_FEATURES] {
+        let mut features = [0.0; NUMBER_FEATURES];
+        for (i, feature) in features.iter_mut().enumerate() {
+            *feature = i as f32 / 10.0;
+        }
+        features
+    }
+
+    #[test]
+    fn round_trip() {
+        let stored = Stored {
+            id: 0x0123_4567_89ab_cdef_0123_4567_89ab_cdef,
+            fhash: [1; 32],
+            ahash: Some([2; 32]),
+            phash: 0xfedc_ba98,
+            fingerprint: Some(vec![3, 4, 5]),
+
+            tags: Tags {
+                title: "Title".to_owned(),
+                artist: "Artist".to_owned(),
+                track: Some(4),
+                ..Tags::default()
+            },
+            duration: Some(183.5),
+            art: Some("art/cover.jpg".to_owned()),
+            profile: "opus160".to_owned(),
+            loudness: Some(Loudness {
+                track: Gain {
+                    loudness: -9.5,
+                    peak: 0.98,
+                },
+                album: None,
+            }),
+
+            analysis: Analysis::new(features()),
+        };
+
+        let bytes = encode(&stored).unwrap();
+        assert_eq!(version(&bytes), VERSION);
+        let decoded = decode(&bytes).unwrap();
+        assert_eq!(decoded.id, stored.id);
+        assert_eq!(decoded.fhash, stored.fhash);
+        assert_eq!(decoded.ahash, stored.ahash);
+        assert_eq!(decoded.phash, stored.phash);
+        assert_eq!(decoded.fingerprint, stored.fingerprint);
+        assert_eq!(decoded.tags.title, "Title");
+        assert_eq!(decoded.tags.artist, "Artist");
+        assert_eq!(decoded.tags.track, Some(4));
+        assert_eq!(decoded.duration, Some(183.5));
+        assert_eq!(decoded.art, stored.art);
+        assert_eq!(decoded.profile, "opus160");
+        let loudness = decoded.loudness.unwrap();
+        assert_eq!(loudness.track.loudness, -9.5);
+        assert!(loudness.album.is_none());
+        assert!(decoded.analysis == stored.analysis);
+    }
+
+    #[test]
+    fn decode_v0() {
+        // A bincoded StoredV1 with no header, like records from before versioning
+        let mut bytes = vec![7; 32];
+        bytes.extend_from_slice(&0x1234u128.to_le_bytes());
+        for tag in ["Title", "Artist", "Album"] {
+            bytes.extend_from_slice(&(tag.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(tag.as_bytes());
+        }
+        for feature in features() {
+            bytes.extend_from_slice(&feature.to_le_bytes());
+        }
+        assert_eq!(version(&bytes), 0);
+
+        let stored = decode(&bytes).unwrap();
+        assert_eq!(stored.id, 0x1234);
+        assert_eq!(stored.phash, 0x1234);
+        assert_eq!(stored.fhash, [7; 32]);
+        assert_eq!(stored.tags.title, "Title");
+        assert_eq!(stored.tags.artist, "Artist");
+        assert_eq!(stored.tags.album, "Album");
+        assert_eq!(stored.profile, LEGACY_PROFILE);
+        assert!(stored.fingerprint.is_none());
+        assert!(stored.loudness.is_none());
+        assert!(stored.analysis == Analysis::new(features()));
+
+        // Version 1 is the same record behind a header
+        let mut v1 = MAGIC.to_vec();
+        v1.extend_from_slice(&1u16.to_be_bytes());
+        v1.extend_from_slice(&bytes);
+        assert_eq!(version(&v1), 1);
+        assert_eq!(decode(&v1).unwrap().tags.title, "Title");
+    }
+}
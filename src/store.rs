@@ -0,0 +1,247 @@
+use sled::Db;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::db::{fmt_id, parse_id, Stored};
+use crate::error::WusicError;
+use crate::profile::Profiles;
+
+// Directories in the store that don't hold songs
+const RESERVED: [&str; 2] = ["art", "quarantine"];
+
+// Longest file or directory name made from tags, in chars
+const MAX_NAME: usize = 100;
+
+// How song files are arranged in the store
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Layout {
+    // <id>.ext
+    Flat,
+    // ab/cd/<id>.ext
+    Sharded,
+    // Artist/Album/NN Title [<id>].ext
+    Readable,
+}
+
+impl Layout {
+    pub const NAMES: [&'static str; 3] = ["flat", "sharded", "readable"];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Flat => "flat",
+            Layout::Sharded => "sharded",
+            Layout::Readable => "readable",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Layout> {
+        match name {
+            "flat" => Some(Layout::Flat),
+            "sharded" => Some(Layout::Sharded),
+            "readable" => Some(Layout::Readable),
+            _ => None,
+        }
+    }
+
+    // Layout recorded in the db, stores from before layouts were flat
+    pub fn load(db: &Db) -> Result<Layout, WusicError> {
+        Ok(match db.open_tree("meta")?.get("layout")? {
+            Some(name) => {
+                let name = String::from_utf8_lossy(&name);
+                Layout::from_name(&name).ok_or_else(|| WusicError::UnknownLayout(name.into()))?
+            }
+            None => Layout::Flat,
+        })
+    }
+
+    pub fn save(self, db: &Db) -> Result<(), WusicError> {
+        db.open_tree("meta")?.insert("layout", self.name())?;
+        Ok(())
+    }
+}
+
+// The music store, and how songs are laid out in it
+pub struct Store {
+    pub path: PathBuf,
+    pub layout: Layout,
+    pub profiles: Profiles,
+}
+
+impl Store {
+    // Path of a stored song, every command finds songs through this
+    pub fn song_path(&self, stored: &Stored) -> Result<PathBuf, WusicError> {
+        let id = fmt_id(stored.id);
+        let extension = self.profiles.extension(&stored.profile)?;
+        Ok(match self.layout {
+            Layout::Flat => self.path.join(format!("{}.{}", id, extension)),
+            Layout::Sharded => {
+                // ULIDs start with a timestamp, so shard on their random end
+                let shard = stored.id as u16;
+                self.path
+                    .join(format!("{:02x}", shard >> 8))
+                    .join(format!("{:02x}", shard & 0xff))
+                    .join(format!("{}.{}", id, extension))
+            }
+            Layout::Readable => {
+                let tags = &stored.tags;
+                let artist = if tags.album_artist.is_empty() {
+                    &tags.artist
+                } else {
+                    &tags.album_artist
+                };
+                // The id keeps songs with the same tags from overwriting each other
+                let name = match tags.track {
+                    Some(track) => format!("{:02} {}", track, sanitize(&tags.title, "Untitled")),
+                    None => sanitize(&tags.title, "Untitled"),
+                };
+                self.path
+                    .join(sanitize(artist, "Unknown Artist"))
+                    .join(sanitize(&tags.album, "Unknown Album"))
+                    .join(format!("{} [{}].{}", name, id, extension))
+            }
+        })
+    }
+
+    // Moves a song file to where a stored song belongs
+    pub fn place(&self, from: &Path, stored: &Stored) -> Result<PathBuf, WusicError> {
+        let to = self.song_path(stored)?;
+        if to != from {
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(from, &to)?;
+            self.remove_empty_dirs(from);
+        }
+        Ok(to)
+    }
+
+    // Moves a song file when its path changes, like when its tags were edited
+    pub fn relocate(&self, old: &Stored, new: &Stored) -> Result<(), WusicError> {
+        let from = self.song_path(old)?;
+        if from.exists() {
+            self.place(&from, new)?;
+        }
+        Ok(())
+    }
+
+    // Whether a path is somewhere songs can be kept
+    pub fn holds_songs(&self, path: &Path) -> bool {
+        match path.strip_prefix(&self.path) {
+            Ok(relative) => !relative
+                .components()
+                .next()
+                .map(|c| RESERVED.iter().any(|r| c.as_os_str() == *r))
+                .unwrap_or(false),
+            Err(_) => false,
+        }
+    }
+
+    // Removes the directories a song was moved out of, if nothing else is left in them
+    pub fn remove_empty_dirs(&self, path: &Path) {
+        remove_empty_dirs(&self.path, path);
+    }
+}
+
+// Removes the empty directories a file was in, up to a root
+pub fn remove_empty_dirs(root: &Path, path: &Path) {
+    let mut dir = path.parent();
+    while let Some(d) = dir {
+        if d == root || !d.starts_with(root) || fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+}
+
+// Id of a song from its file name, in any layout
+pub fn path_id(path: &Path) -> Option<u128> {
+    let stem = path.file_stem()?.to_str()?;
+    match stem.strip_suffix(']').and_then(|s| s.rsplit_once('[')) {
+        Some((_, id)) => parse_id(id),
+        None => parse_id(stem),
+    }
+}
+
+// Makes a tag safe to use as a file or directory name
+pub fn sanitize(value: &str, fallback: &str) -> String {
+    let name: String = value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .take(MAX_NAME)
+        .collect();
+    // Trailing dots and spaces are dropped by some filesystems
+    let name = name
+        .trim_start()
+        .trim_end_matches(|c: char| c == '.' || c.is_whitespace());
+    if name.is_empty() || name.chars().all(|c| c == '.') {
+        fallback.to_owned()
+    } else {
+        name.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::new_id;
+
+    #[test]
+    fn sanitizes_names() {
+        assert_eq!(sanitize("AC/DC", "Unknown"), "AC_DC");
+        assert_eq!(
+            sanitize("What?: <Live> \\ \"1\" | *", "Unknown"),
+            "What__ _Live_ _ _1_ _ _"
+        );
+        assert_eq!(sanitize("tab\there", "Unknown"), "tab_here");
+        // Names filesystems would change or that mean something else
+        assert_eq!(sanitize("Album. . ", "Unknown"), "Album");
+        assert_eq!(sanitize("  Padded  ", "Unknown"), "Padded");
+        assert_eq!(sanitize("..", "Unknown"), "Unknown");
+        assert_eq!(sanitize(".", "Unknown"), "Unknown");
+        assert_eq!(sanitize("", "Unknown"), "Unknown");
+        assert_eq!(sanitize(".hidden", "Unknown"), ".hidden");
+    }
+
+    #[test]
+    fn limits_name_length() {
+        let long = "a".repeat(MAX_NAME * 2);
+        assert_eq!(sanitize(&long, "Unknown").chars().count(), MAX_NAME);
+        // Counted in chars, so names are never cut inside one
+        let wide = "é".repeat(MAX_NAME + 1);
+        assert_eq!(sanitize(&wide, "Unknown"), "é".repeat(MAX_NAME));
+        // Cutting can leave a trailing dot
+        let dotted = format!("{}.b", "a".repeat(MAX_NAME - 1));
+        assert_eq!(sanitize(&dotted, "Unknown"), "a".repeat(MAX_NAME - 1));
+    }
+
+    #[test]
+    fn finds_ids_in_any_layout() {
+        let id = new_id();
+        let name = fmt_id(id);
+        assert_eq!(path_id(Path::new(&format!("{}.opus", name))), Some(id));
+        assert_eq!(
+            path_id(Path::new(&format!("ab/cd/{}.flac", name))),
+            Some(id)
+        );
+        assert_eq!(
+            path_id(Path::new(&format!("Artist/Album/01 Title [{}].opus", name))),
+            Some(id)
+        );
+        // Brackets in a title don't hide the id
+        assert_eq!(
+            path_id(Path::new(&format!(
+                "Artist/Album/Song [Live] [{}].m4a",
+                name
+            ))),
+            Some(id)
+        );
+        assert_eq!(path_id(Path::new("Artist/Album/01 Title.opus")), None);
+        assert_eq!(path_id(Path::new("Artist/Album/Song [Live].opus")), None);
+        assert_eq!(path_id(Path::new("1234abcd_5678.opus")), None);
+    }
+}
@@ -0,0 +1,235 @@
+use ffmpeg::format::context::Input;
+use ffmpeg::{media, Dictionary};
+
+use crate::art::Picture;
+use crate::db::normalize;
+use crate::profile::Profile;
+
+pub use crate::schema::Tags;
+
+// Lowercased tag keys that containers use for each tag, in order of preference
+const TITLE: &[&str] = &["title", "tit2", "\u{a9}nam", "inam"];
+const ARTIST: &[&str] = &["artist", "tpe1", "\u{a9}art", "iart", "author"];
+const ALBUM: &[&str] = &["album", "talb", "\u{a9}alb", "iprd"];
+const ALBUM_ARTIST: &[&str] = &[
+    "album_artist",
+    "albumartist",
+    "album artist",
+    "tpe2",
+    "aart",
+    "ensemble",
+];
+const TRACK: &[&str] = &["track", "tracknumber", "trck", "trkn"];
+const DISC: &[&str] = &["disc", "discnumber", "tpos", "disk"];
+const DATE: &[&str] = &[
+    "date",
+    "tdrc",
+    "year",
+    "tyer",
+    "\u{a9}day",
+    "icrd",
+    "originaldate",
+];
+const GENRE: &[&str] = &["genre", "tcon", "\u{a9}gen", "ignr"];
+const COMPOSER: &[&str] = &["composer", "tcom", "\u{a9}wrt"];
+
+impl Tags {
+    // Reads the tags of a song from the container and all of its audio streams
+    pub fn read(format: &Input) -> Tags {
+        let found = raw(format);
+        let get = |aliases: &[&str]| {
+            aliases
+                .iter()
+                .find_map(|alias| {
+                    found
+                        .iter()
+                        .find(|(k, v)| k.as_str() == *alias && !v.is_empty())
+                })
+                .map(|(_, v)| v.clone())
+                .unwrap_or_default()
+        };
+
+        Tags {
+            title: get(TITLE),
+            artist: get(ARTIST),
+            album: get(ALBUM),
+            album_artist: get(ALBUM_ARTIST),
+            track: parse_number(&get(TRACK)),
+            disc: parse_number(&get(DISC)),
+            date: get(DATE),
+            genre: get(GENRE),
+            composer: get(COMPOSER),
+        }
+    }
+
+    // Asks for new tags, using the current ones as defaults
+    pub fn prompt(&self) -> Tags {
+        Tags {
+            title: prompt_string("title", "Song title", &self.title),
+            artist: prompt_string("artist", "Song artist", &self.artist),
+            album: prompt_string("album", "Song album", &self.album),
+            album_artist: prompt_string("album_artist", "Album artist", &self.album_artist),
+            track: parse_number(&prompt_string(
+                "track",
+                "Track number",
+                &self.track.map(|n| n.to_string()).unwrap_or_default(),
+            )),
+            disc: parse_number(&prompt_string(
+                "disc",
+                "Disc number",
+                &self.disc.map(|n| n.to_string()).unwrap_or_default(),
+            )),
+            date: prompt_string("date", "Release date", &self.date),
+            genre: prompt_string("genre", "Genre", &self.genre),
+            composer: prompt_string("composer", "Composer", &self.composer),
+        }
+    }
+
+    // Fills in missing tags from other tags
+    pub fn fill_from(&mut self, other: &Tags) {
+        let fill = |tag: &mut String, other: &String| {
+            if tag.is_empty() {
+                *tag = other.clone();
+            }
+        };
+        fill(&mut self.title, &other.title);
+        fill(&mut self.artist, &other.artist);
+        fill(&mut self.album, &other.album);
+        fill(&mut self.album_artist, &other.album_artist);
+        fill(&mut self.date, &other.date);
+        fill(&mut self.genre, &other.genre);
+        fill(&mut self.composer, &other.composer);
+        self.track = self.track.or(other.track);
+        self.disc = self.disc.or(other.disc);
+    }
+
+    // Normalized album artist and album, if the song is on an album
+    pub fn album_key(&self) -> Option<(String, String)> {
+        if self.album.trim().is_empty() {
+            return None;
+        }
+        let artist = if self.album_artist.trim().is_empty() {
+            &self.artist
+        } else {
+            &self.album_artist
+        };
+        Some((normalize(artist), normalize(&self.album)))
+    }
+
+    // Vorbis comments for the tags that are set
+    pub fn comments(&self) -> Vec<(&'static str, String)> {
+        let mut comments = vec![
+            ("TITLE", self.title.clone()),
+            ("ARTIST", self.artist.clone()),
+            ("ALBUM", self.album.clone()),
+            ("ALBUMARTIST", self.album_artist.clone()),
+            (
+                "TRACKNUMBER",
+                self.track.map(|n| n.to_string()).unwrap_or_default(),
+            ),
+            (
+                "DISCNUMBER",
+                self.disc.map(|n| n.to_string()).unwrap_or_default(),
+            ),
+            ("DATE", self.date.clone()),
+            ("GENRE", self.genre.clone()),
+            ("COMPOSER", self.composer.clone()),
+        ];
+        comments.retain(|(_, value)| !value.is_empty());
+        comments
+    }
+
+    // Metadata with the tags, art and any extra comments, for writing songs with a profile
+    pub fn metadata(
+        &self,
+        profile: &Profile,
+        picture: Option<&Picture>,
+        extra: &[(String, String)],
+    ) -> Dictionary<'static> {
+        let mut metadata = Dictionary::new();
+        for (key, value) in self.comments() {
+            let key = if profile.mp4() { mp4_key(key) } else { key };
+            metadata.set(key, &value);
+        }
+        if let Some(picture) = picture {
+            metadata.set("METADATA_BLOCK_PICTURE", &picture.block());
+        }
+        for (key, value) in extra {
+            metadata.set(key, value);
+        }
+        metadata
+    }
+}
+
+// Name the mp4 muxer knows a Vorbis comment by
+fn mp4_key(key: &'static str) -> &'static str {
+    match key {
+        "TITLE" => "title",
+        "ARTIST" => "artist",
+        "ALBUM" => "album",
+        "ALBUMARTIST" => "album_artist",
+        "TRACKNUMBER" => "track",
+        "DISCNUMBER" => "disc",
+        "DATE" => "date",
+        "GENRE" => "genre",
+        "COMPOSER" => "composer",
+        key => key,
+    }
+}
+
+// Gets every tag of a song with lowercased keys, container tags first
+pub fn raw(format: &Input) -> Vec<(String, String)> {
+    let mut found: Vec<(String, String)> = format
+        .metadata()
+        .iter()
+        .map(|(k, v)| (k.to_lowercase(), v.trim().to_owned()))
+        .collect();
+    for stream in format.streams() {
+        if stream.parameters().medium() == media::Type::Audio {
+            found.extend(
+                stream
+                    .metadata()
+                    .iter()
+                    .map(|(k, v)| (k.to_lowercase(), v.trim().to_owned())),
+            );
+        }
+    }
+    found
+}
+
+// Gets the loudness tags of a song, so they survive retagging
+pub fn gain(format: &Input) -> Vec<(String, String)> {
+    raw(format)
+        .into_iter()
+        .filter(|(k, _)| k.starts_with("r128_") || k.starts_with("replaygain_"))
+        .map(|(k, v)| (k.to_uppercase(), v))
+        .collect()
+}
+
+// Gets the duration of a song in seconds
+pub fn duration(format: &Input) -> Option<f64> {
+    if format.duration() > 0 {
+        Some(format.duration() as f64 / ffmpeg::ffi::AV_TIME_BASE as f64)
+    } else {
+        None
+    }
+}
+
+// Parses track and disc numbers like "3" or "3/12"
+fn parse_number(value: &str) -> Option<u32> {
+    value.split('/').next()?.trim().parse().ok()
+}
+
+// Asks for a string
+fn prompt_string(name: &str, message: &str, default: &str) -> String {
+    requestty::prompt_one(
+        requestty::Question::input(name)
+            .message(message)
+            .default(default)
+            .build(),
+    )
+    .unwrap()
+    .as_string()
+    .unwrap()
+    .to_owned()
+}
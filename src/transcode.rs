@@ -0,0 +1,404 @@
+use ffmpeg::codec::capabilities::Capabilities;
+use ffmpeg::format::{sample, Sample};
+use ffmpeg::software::resampling;
+use ffmpeg::{
+    codec, encoder, filter, format, frame, media, ChannelLayout, Dictionary, Packet, Rational,
+};
+
+use std::path::Path;
+
+use crate::error::WusicError;
+use crate::profile::Profile;
+
+// Decodes, resamples and encodes the audio of a song
+struct Transcoder {
+    stream: usize,
+    filter: filter::Graph,
+    decoder: codec::decoder::Audio,
+    encoder: codec::encoder::Audio,
+    encoder_time_base: Rational,
+    out_time_base: Rational,
+}
+
+impl Transcoder {
+    fn new(
+        ictx: &format::context::Input,
+        octx: &mut format::context::Output,
+        profile: &Profile,
+    ) -> Result<Transcoder, WusicError> {
+        let input = ictx
+            .streams()
+            .best(media::Type::Audio)
+            .ok_or(ffmpeg::Error::StreamNotFound)?;
+        let in_time_base = input.time_base();
+        let context = codec::context::Context::from_parameters(input.parameters())?;
+        let mut decoder = context.decoder().audio()?;
+
+        // Some files don't say how their channels are laid out
+        if decoder.channel_layout().is_empty() {
+            decoder.set_channel_layout(ChannelLayout::default(decoder.channels() as i32));
+        }
+
+        let audio = encoder::find_by_name(&profile.codec)
+            .ok_or(ffmpeg::Error::EncoderNotFound)?
+            .audio()?;
+        let global = octx
+            .format()
+            .flags()
+            .contains(format::flag::Flags::GLOBAL_HEADER);
+
+        let mut output = octx.add_stream(audio)?;
+        let context = codec::context::Context::from_parameters(output.parameters())?;
+        let mut encoder = context.encoder().audio()?;
+
+        let channel_layout = audio
+            .channel_layouts()
+            .map(|cls| cls.best(decoder.channel_layout().channels()))
+            .unwrap_or(ChannelLayout::STEREO);
+        let rate = profile.sample_rate.unwrap_or_else(|| decoder.rate()) as i32;
+
+        if global {
+            encoder.set_flags(codec::flag::Flags::GLOBAL_HEADER);
+        }
+        encoder.set_rate(rate);
+        encoder.set_channel_layout(channel_layout);
+        encoder.set_channels(channel_layout.channels());
+        // Keep the decoded precision if the encoder takes it, or the most it does, so flac
+        // doesn't cut 24 bit songs down to 16
+        let formats: Vec<Sample> = audio
+            .formats()
+            .map(|formats| formats.collect())
+            .unwrap_or_default();
+        let sample_format = if formats.contains(&decoder.format()) {
+            decoder.format()
+        } else {
+            *formats
+                .iter()
+                .rev()
+                .max_by_key(|format| format.bytes())
+                .ok_or(ffmpeg::Error::InvalidData)?
+        };
+        encoder.set_format(sample_format);
+        encoder.set_bit_rate(profile.bit_rate().unwrap_or(0));
+        encoder.set_time_base((1, rate));
+        output.set_time_base((1, rate));
+
+        let encoder = encoder.open_as(audio)?;
+        output.set_parameters(&encoder);
+
+        let filter = Transcoder::filter(in_time_base, &decoder, &encoder)?;
+
+        Ok(Transcoder {
+            stream: input.index(),
+            filter,
+            decoder,
+            encoder,
+            encoder_time_base: Rational::new(1, rate),
+            out_time_base: Rational::new(1, rate),
+        })
+    }
+
+    // Filter graph converting decoded frames into what the encoder takes
+    fn filter(
+        time_base: Rational,
+        decoder: &codec::decoder::Audio,
+        encoder: &codec::encoder::Audio,
+    ) -> Result<filter::Graph, WusicError> {
+        let mut filter = filter::Graph::new();
+
+        let args = format!(
+            "time_base={}:sample_rate={}:sample_fmt={}:channel_layout=0x{:x}",
+            time_base,
+            decoder.rate(),
+            decoder.format().name(),
+            decoder.channel_layout().bits()
+        );
+        filter.add(
+            &filter::find("abuffer").ok_or(ffmpeg::Error::FilterNotFound)?,
+            "in",
+            &args,
+        )?;
+        filter.add(
+            &filter::find("abuffersink").ok_or(ffmpeg::Error::FilterNotFound)?,
+            "out",
+            "",
+        )?;
+
+        {
+            let mut out = filter.get("out").ok_or(ffmpeg::Error::FilterNotFound)?;
+            out.set_sample_format(encoder.format());
+            out.set_channel_layout(encoder.channel_layout());
+            out.set_sample_rate(encoder.rate());
+        }
+
+        filter.output("in", 0)?.input("out", 0)?.parse("anull")?;
+        filter.validate()?;
+
+        // Encoders like libopus need frames of an exact size
+        if let Some(codec) = encoder.codec() {
+            if !codec
+                .capabilities()
+                .contains(Capabilities::VARIABLE_FRAME_SIZE)
+            {
+                filter
+                    .get("out")
+                    .ok_or(ffmpeg::Error::FilterNotFound)?
+                    .sink()
+                    .set_frame_size(encoder.frame_size());
+            }
+        }
+
+        Ok(filter)
+    }
+
+    // Writes out every packet the encoder has ready
+    fn write_encoded(&mut self, octx: &mut format::context::Output) -> Result<(), WusicError> {
+        let mut encoded = Packet::empty();
+        while self.encoder.receive_packet(&mut encoded).is_ok() {
+            encoded.set_stream(0);
+            encoded.rescale_ts(self.encoder_time_base, self.out_time_base);
+            encoded.write_interleaved(octx)?;
+        }
+        Ok(())
+    }
+
+    // Encodes every frame the filter has ready
+    fn encode_filtered(&mut self, octx: &mut format::context::Output) -> Result<(), WusicError> {
+        let mut filtered = frame::Audio::empty();
+        while self
+            .filter
+            .get("out")
+            .ok_or(ffmpeg::Error::FilterNotFound)?
+            .sink()
+            .frame(&mut filtered)
+            .is_ok()
+        {
+            self.encoder.send_frame(&filtered)?;
+            self.write_encoded(octx)?;
+        }
+        Ok(())
+    }
+
+    // Filters and encodes every frame the decoder has ready
+    fn filter_decoded(&mut self, octx: &mut format::context::Output) -> Result<(), WusicError> {
+        let mut decoded = frame::Audio::empty();
+        while self.decoder.receive_frame(&mut decoded).is_ok() {
+            let timestamp = decoded.timestamp();
+            decoded.set_pts(timestamp);
+            self.filter
+                .get("in")
+                .ok_or(ffmpeg::Error::FilterNotFound)?
+                .source()
+                .add(&decoded)?;
+            self.encode_filtered(octx)?;
+        }
+        Ok(())
+    }
+
+    // Drains the decoder, filter and encoder
+    fn finish(&mut self, octx: &mut format::context::Output) -> Result<(), WusicError> {
+        self.decoder.send_eof()?;
+        self.filter_decoded(octx)?;
+
+        self.filter
+            .get("in")
+            .ok_or(ffmpeg::Error::FilterNotFound)?
+            .source()
+            .flush()?;
+        self.encode_filtered(octx)?;
+
+        self.encoder.send_eof()?;
+        self.write_encoded(octx)
+    }
+}
+
+// Transcodes the audio of a song with a profile, tagging it with metadata
+pub fn transcode(
+    input: &Path,
+    output: &Path,
+    profile: &Profile,
+    metadata: Dictionary,
+) -> Result<(), WusicError> {
+    let mut ictx = format::input(&input)?;
+    let mut octx = format::output_as(&output, &profile.format)?;
+    let mut transcoder = Transcoder::new(&ictx, &mut octx, profile)?;
+
+    set_metadata(&mut octx, metadata);
+    octx.write_header()?;
+    // The muxer may pick a different time base when writing the header
+    transcoder.out_time_base = octx
+        .stream(0)
+        .ok_or(ffmpeg::Error::StreamNotFound)?
+        .time_base();
+
+    for (stream, packet) in ictx.packets() {
+        if stream.index() == transcoder.stream {
+            transcoder.decoder.send_packet(&packet)?;
+            transcoder.filter_decoded(&mut octx)?;
+        }
+    }
+    transcoder.finish(&mut octx)?;
+
+    octx.write_trailer()?;
+    Ok(())
+}
+
+// Copies the audio of a song into a format without reencoding, tagging it with metadata
+pub fn remux(
+    input: &Path,
+    output: &Path,
+    format_name: &str,
+    metadata: Dictionary,
+) -> Result<(), WusicError> {
+    let mut ictx = format::input(&input)?;
+    let mut octx = format::output_as(&output, format_name)?;
+
+    let (index, in_time_base) = {
+        let input = ictx
+            .streams()
+            .best(media::Type::Audio)
+            .ok_or(ffmpeg::Error::StreamNotFound)?;
+        let mut stream = octx.add_stream(encoder::find(codec::Id::None))?;
+        stream.set_parameters(input.parameters());
+        // Let the muxer pick its own codec tag
+        unsafe {
+            (*stream.parameters().as_mut_ptr()).codec_tag = 0;
+        }
+        (input.index(), input.time_base())
+    };
+
+    set_metadata(&mut octx, metadata);
+    octx.write_header()?;
+    let out_time_base = octx
+        .stream(0)
+        .ok_or(ffmpeg::Error::StreamNotFound)?
+        .time_base();
+
+    for (stream, mut packet) in ictx.packets() {
+        if stream.index() == index {
+            packet.rescale_ts(in_time_base, out_time_base);
+            packet.set_position(-1);
+            packet.set_stream(0);
+            packet.write_interleaved(&mut octx)?;
+        }
+    }
+
+    octx.write_trailer()?;
+    Ok(())
+}
+
+// Sets tags on the container and the audio stream, since Ogg only reads them from the stream
+fn set_metadata(octx: &mut format::context::Output, metadata: Dictionary) {
+    if let Some(mut stream) = octx.stream_mut(0) {
+        stream.set_metadata(metadata.clone());
+    }
+    octx.set_metadata(metadata);
+}
+
+// Decodes the audio of a song into interleaved f32 samples
+pub struct Samples {
+    ictx: format::context::Input,
+    index: usize,
+    decoder: codec::decoder::Audio,
+    resampler: resampling::Context,
+    pub layout: ChannelLayout,
+    pub channels: usize,
+    pub rate: u32,
+}
+
+impl Samples {
+    // Opens a song, converting it to a channel layout and sample rate if given
+    pub fn open(
+        path: &Path,
+        layout: Option<ChannelLayout>,
+        rate: Option<u32>,
+    ) -> Result<Samples, WusicError> {
+        let ictx = format::input(&path)?;
+        let (index, mut decoder) = {
+            let input = ictx
+                .streams()
+                .best(media::Type::Audio)
+                .ok_or(ffmpeg::Error::StreamNotFound)?;
+            let context = codec::context::Context::from_parameters(input.parameters())?;
+            (input.index(), context.decoder().audio()?)
+        };
+
+        // Some files don't say how their channels are laid out
+        if decoder.channel_layout().is_empty() {
+            decoder.set_channel_layout(ChannelLayout::default(decoder.channels() as i32));
+        }
+        let layout = layout.unwrap_or_else(|| decoder.channel_layout());
+        let rate = rate.unwrap_or_else(|| decoder.rate());
+        let resampler = decoder.resampler(Sample::F32(sample::Type::Packed), layout, rate)?;
+
+        Ok(Samples {
+            ictx,
+            index,
+            decoder,
+            resampler,
+            layout,
+            channels: layout.channels() as usize,
+            rate,
+        })
+    }
+
+    // Passes all samples of the song to a function, a frame at a time
+    pub fn each(mut self, mut f: impl FnMut(&[f32])) -> Result<(), WusicError> {
+        let mut decoded = frame::Audio::empty();
+        let channels = self.channels;
+        let mut emit = |resampled: &frame::Audio| {
+            let len = resampled.samples() * channels * 4;
+            let samples: Vec<f32> = resampled.data(0)[..len]
+                .chunks_exact(4)
+                .map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+                .collect();
+            f(&samples);
+        };
+        let mut receive = |decoder: &mut codec::decoder::Audio,
+                           resampler: &mut resampling::Context|
+         -> Result<(), WusicError> {
+            while decoder.receive_frame(&mut decoded).is_ok() {
+                if let Some(mut resampled) = output_frame(resampler, decoded.samples()) {
+                    resampler.run(&decoded, &mut resampled)?;
+                    emit(&resampled);
+                }
+            }
+            Ok(())
+        };
+
+        for (stream, packet) in self.ictx.packets() {
+            if stream.index() == self.index {
+                self.decoder.send_packet(&packet)?;
+                receive(&mut self.decoder, &mut self.resampler)?;
+            }
+        }
+        self.decoder.send_eof()?;
+        receive(&mut self.decoder, &mut self.resampler)?;
+
+        // Get out what the resampler still holds
+        while let Some(mut resampled) = output_frame(&self.resampler, 0) {
+            self.resampler.flush(&mut resampled)?;
+            if resampled.samples() == 0 {
+                break;
+            }
+            emit(&resampled);
+        }
+        Ok(())
+    }
+}
+
+// A frame with room for everything the resampler can give for a number of input samples,
+// frames sized to their input would leave the rest buffered in it. None if it has nothing.
+fn output_frame(resampler: &resampling::Context, samples: usize) -> Option<frame::Audio> {
+    let capacity =
+        unsafe { ffmpeg::ffi::swr_get_out_samples(resampler.as_ptr() as *mut _, samples as i32) };
+    if capacity <= 0 {
+        return None;
+    }
+    let output = resampler.output();
+    Some(frame::Audio::new(
+        output.format,
+        capacity as usize,
+        output.channel_layout,
+    ))
+}